@@ -0,0 +1,42 @@
+use std::io::{self, Cursor, Sink};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use piko_core::ast::{Parser, PikoAst};
+use piko_core::vm::{Engine, VM};
+
+const LOOPS: &str = include_str!("../../examples/loops.pyx");
+
+/// The loops example scaled up: the same counting loop run to "alm" (1000)
+/// iterations, keeping a running total.
+const COUNTING: &str = "
+    (a counter \"a\")
+    (a total \"a\")
+    (l (<= counter \"alm\") (a counter (+ counter \"a\")) (a total (+ total counter)))
+";
+
+fn vm(engine: Engine) -> VM<Sink, Cursor<String>> {
+    let mut vm = VM::new(io::sink(), Cursor::new(String::new()));
+    vm.set_engine(engine);
+    vm
+}
+
+fn bench_program(c: &mut Criterion, name: &str, source: &str) {
+    let program = Parser::parse_program(source).unwrap();
+    for (engine, label) in [(Engine::TreeWalking, "tree"), (Engine::Bytecode, "bytecode")] {
+        c.bench_function(&format!("{}/{}", name, label), |b| {
+            b.iter_batched(
+                || (vm(engine), program.clone()),
+                |(mut vm, program): (_, PikoAst)| vm.execute(program).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+}
+
+fn loops(c: &mut Criterion) {
+    bench_program(c, "loops", LOOPS);
+    bench_program(c, "counting", COUNTING);
+}
+
+criterion_group!(benches, loops);
+criterion_main!(benches);
@@ -0,0 +1,151 @@
+use std::fmt;
+
+use crate::utils::error::{VMError, VMResult};
+use super::PikoAst;
+use super::expressions::Node;
+use super::lexer::{Lexer, Token, TokenKind, Trivia, TriviaKind};
+use super::parser::{self, Parser, MAX_NESTING};
+use super::span::Span;
+
+/// A token together with the whitespace and comments that precede it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CstToken<'a> {
+    pub leading: Vec<Trivia<'a>>,
+    pub token: Token<'a>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CstList<'a> {
+    pub open: CstToken<'a>,
+    pub children: Vec<CstNode<'a>>,
+    pub close: CstToken<'a>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CstNode<'a> {
+    Token(CstToken<'a>),
+    List(CstList<'a>),
+}
+
+/// Lossless syntax tree of a Piko source file. Printing it reproduces the
+/// input byte for byte, comments and spacing included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cst<'a> {
+    pub forms: Vec<CstNode<'a>>,
+    pub trailing: Vec<Trivia<'a>>,
+}
+
+impl<'a> Cst<'a> {
+    pub fn parse(input: &'a str) -> VMResult<Self> {
+        let mut lexer = Lexer::new(input);
+        let mut forms = Vec::new();
+        let mut open_lists: Vec<(CstToken<'a>, Vec<CstNode<'a>>)> = Vec::new();
+        
+        loop {
+            let mut leading = Vec::new();
+            while let Some(trivia) = lexer.next_trivia() {
+                leading.push(trivia);
+            }
+            
+            let token = match lexer.next_token()? {
+                Some(token) => CstToken { leading, token },
+                None => {
+                    if let Some((open, _)) = open_lists.first() {
+                        return Err(VMError::ParseError(format!("Unclosed '(': missing {} ')'", open_lists.len()))
+                            .at(open.token.span));
+                    }
+                    return Ok(Cst { forms, trailing: leading });
+                }
+            };
+            
+            let node = match token.token.kind {
+                TokenKind::LParen if open_lists.len() == MAX_NESTING => {
+                    return Err(parser::nesting_error(token.token.span));
+                }
+                TokenKind::LParen => {
+                    open_lists.push((token, Vec::new()));
+                    continue;
+                }
+                TokenKind::RParen => {
+                    let (open, children) = open_lists.pop()
+                        .ok_or_else(|| VMError::ParseError("Unexpected ')'".to_string()).at(token.token.span))?;
+                    CstNode::List(CstList { open, children, close: token })
+                }
+                TokenKind::Str | TokenKind::Ident | TokenKind::Operator => CstNode::Token(token),
+            };
+            
+            match open_lists.last_mut() {
+                Some((_, children)) => children.push(node),
+                None => forms.push(node),
+            }
+        }
+    }
+    
+    pub fn to_ast(&self) -> VMResult<PikoAst> {
+        let program = self.forms.iter()
+            .map(|form| form.to_node().map(PikoAst::Expression))
+            .collect::<VMResult<Vec<_>>>()?;
+        Ok(PikoAst::Program(program))
+    }
+}
+
+impl<'a> CstNode<'a> {
+    pub fn to_node(&self) -> VMResult<Node> {
+        Parser::parse_cst_node(self)
+    }
+    
+    pub fn span(&self) -> Span {
+        match self {
+            CstNode::Token(token) => token.token.span,
+            CstNode::List(list) => list.open.token.span.to(list.close.token.span),
+        }
+    }
+    
+    /// Whitespace and comments directly before this node.
+    pub fn leading(&self) -> &[Trivia<'a>] {
+        match self {
+            CstNode::Token(token) => &token.leading,
+            CstNode::List(list) => &list.open.leading,
+        }
+    }
+    
+    pub fn comments(&self) -> impl Iterator<Item = &Trivia<'a>> {
+        self.leading().iter().filter(|trivia| trivia.kind == TriviaKind::Comment)
+    }
+}
+
+impl fmt::Display for CstToken<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for trivia in &self.leading {
+            f.write_str(trivia.text)?;
+        }
+        f.write_str(self.token.text)
+    }
+}
+
+impl fmt::Display for CstNode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CstNode::Token(token) => token.fmt(f),
+            CstNode::List(list) => {
+                list.open.fmt(f)?;
+                for child in &list.children {
+                    child.fmt(f)?;
+                }
+                list.close.fmt(f)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Cst<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for form in &self.forms {
+            form.fmt(f)?;
+        }
+        for trivia in &self.trailing {
+            f.write_str(trivia.text)?;
+        }
+        Ok(())
+    }
+}
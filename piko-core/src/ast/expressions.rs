@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 
 use crate::utils::error::VMResult;
+use super::span::Spanned;
 
 pub trait Parseable {
     fn parse(input: &str) -> VMResult<Self> where Self: Sized;
@@ -10,12 +11,16 @@ pub trait Atom {
     fn is_single_letter(&self) -> bool;
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum BinaryOp {
     Add,
     Sub,
     Mul,
     Div,
+    Rem,
+    Pow,
+    Min,
+    Max,
     Lt,
     Gt,
     Le,
@@ -24,36 +29,90 @@ pub enum BinaryOp {
     Ne,
 }
 
+impl BinaryOp {
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        let op = match symbol {
+            "+" => BinaryOp::Add,
+            "-" => BinaryOp::Sub,
+            "*" => BinaryOp::Mul,
+            "/" => BinaryOp::Div,
+            "%" => BinaryOp::Rem,
+            "^" => BinaryOp::Pow,
+            "<?" => BinaryOp::Min,
+            ">?" => BinaryOp::Max,
+            "<" => BinaryOp::Lt,
+            ">" => BinaryOp::Gt,
+            "<=" => BinaryOp::Le,
+            ">=" => BinaryOp::Ge,
+            "==" => BinaryOp::Eq,
+            "!=" => BinaryOp::Ne,
+            _ => return None,
+        };
+        Some(op)
+    }
+    
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+            BinaryOp::Pow => "^",
+            BinaryOp::Min => "<?",
+            BinaryOp::Max => ">?",
+            BinaryOp::Lt => "<",
+            BinaryOp::Gt => ">",
+            BinaryOp::Le => "<=",
+            BinaryOp::Ge => ">=",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+        }
+    }
+}
+
+pub type Node = Spanned<Expression>;
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum Expression {
     Variable(String),
     Literal(String),
-    BinaryOp(Box<Expression>, BinaryOp, Box<Expression>),
-    Output(Box<Expression>),
+    BinaryOp(Box<Node>, BinaryOp, Box<Node>),
+    Output(Box<Node>),
     Input(String),
-    Assign(String, Box<Expression>),
-    Return(Box<Expression>),
-    Call(String, Vec<Expression>),
-    Function(String, Vec<String>, Box<Expression>),
-    Loop(Option<Box<Expression>>, Box<Expression>),
+    Assign(String, Box<Node>),
+    Return(Box<Node>),
+    Call(String, Vec<Node>),
+    Function(String, Vec<String>, Box<Node>),
+    Loop(Option<Box<Node>>, Box<Node>),
+    If(Box<Node>, Box<Node>, Option<Box<Node>>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Not(Box<Node>),
     Break,
     ChainedOp(Vec<ChainOp>),
-    Block(Vec<Expression>),
+    Block(Vec<Node>),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum ChainOp {
     Input(String),
     Output,
-    Assign(String, Box<Expression>),
-    Return(Box<Expression>),
-    Call(String, Vec<Expression>),
-    Function(String, Vec<String>, Box<Expression>),
-    Loop(Option<Box<Expression>>, Box<Expression>),
+    Assign(String, Box<Node>),
+    Return(Box<Node>),
+    Call(String, Vec<Node>),
+    Function(String, Vec<String>, Box<Node>),
+    Loop(Option<Box<Node>>, Box<Node>),
     Break,
 }
 
 impl Parseable for Expression {
+    fn parse(input: &str) -> VMResult<Self> {
+        Node::parse(input).map(|node| node.node)
+    }
+}
+
+impl Parseable for Node {
     fn parse(input: &str) -> VMResult<Self> {
         super::parser::Parser::parse_expression(input)
     }
@@ -0,0 +1,269 @@
+use crate::utils::error::{VMError, VMResult};
+use super::span::{Position, Span};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    LParen,
+    RParen,
+    Str,
+    Ident,
+    Operator,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriviaKind {
+    Whitespace,
+    Comment,
+}
+
+/// Source text between tokens that carries no meaning for the parser.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trivia<'a> {
+    pub kind: TriviaKind,
+    pub text: &'a str,
+    pub span: Span,
+}
+
+pub struct Lexer<'a> {
+    input: &'a str,
+    pos: Position,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Lexer { input, pos: Position::start() }
+    }
+    
+    pub fn tokenize(input: &'a str) -> VMResult<Vec<Token<'a>>> {
+        let mut lexer = Lexer::new(input);
+        let mut tokens = Vec::new();
+        while let Some(token) = lexer.next_token()? {
+            tokens.push(token);
+        }
+        Ok(tokens)
+    }
+    
+    /// Tokenizes the whole input, collecting errors instead of stopping at
+    /// the first one. Invalid characters and words are left out of the tokens.
+    pub fn tokenize_recovering(input: &'a str) -> (Vec<Token<'a>>, Vec<VMError>) {
+        let mut lexer = Lexer::new(input);
+        let mut tokens = Vec::new();
+        let mut errors = Vec::new();
+        loop {
+            match lexer.next_token() {
+                Ok(Some(token)) => tokens.push(token),
+                Ok(None) => break,
+                Err(error) => errors.push(error),
+            }
+        }
+        (tokens, errors)
+    }
+    
+    pub fn next_token(&mut self) -> VMResult<Option<Token<'a>>> {
+        self.skip_trivia();
+        
+        let start = self.pos;
+        let kind = match self.peek() {
+            None => return Ok(None),
+            Some('(') => {
+                self.bump();
+                TokenKind::LParen
+            }
+            Some(')') => {
+                self.bump();
+                TokenKind::RParen
+            }
+            Some('"') => {
+                self.lex_string(start)?;
+                TokenKind::Str
+            }
+            Some(ch) if Self::is_operator_char(ch) => {
+                self.bump_while(Self::is_operator_char);
+                TokenKind::Operator
+            }
+            Some(ch) if ch.is_alphanumeric() || ch == '_' => {
+                self.bump_while(|c| c.is_alphanumeric() || c == '_');
+                let word = &self.input[start.offset..self.pos.offset];
+                Self::check_identifier(word).map_err(|e| e.at(Span::new(start, self.pos)))?;
+                TokenKind::Ident
+            }
+            Some(ch) => {
+                self.bump();
+                return Err(VMError::ParseError(format!("Unexpected character '{}'", ch))
+                    .at(Span::new(start, self.pos)));
+            }
+        };
+        
+        Ok(Some(Token {
+            kind,
+            text: &self.input[start.offset..self.pos.offset],
+            span: Span::new(start, self.pos),
+        }))
+    }
+    
+    pub fn is_operator_char(ch: char) -> bool {
+        matches!(ch, '+' | '-' | '*' | '/' | '%' | '^' | '<' | '>' | '=' | '!' | '?' | '&' | '|')
+    }
+    
+    fn check_identifier(word: &str) -> VMResult<()> {
+        if word.chars().all(|c| c.is_ascii_digit()) {
+            return Err(VMError::ParseError(format!(
+                "Numbers are not allowed: '{}' (write values as base-26 letters, e.g. \"a\" = 1)",
+                word
+            )));
+        }
+        match word.chars().find(|c| !c.is_ascii_lowercase()) {
+            Some(c) => Err(VMError::ParseError(format!(
+                "Invalid identifier '{}': found '{}', identifiers may only contain a-z",
+                word, c
+            ))),
+            None => Ok(()),
+        }
+    }
+    
+    fn lex_string(&mut self, start: Position) -> VMResult<()> {
+        self.bump();
+        let mut error = None;
+        loop {
+            let escape_start = self.pos;
+            match self.bump() {
+                None => break,
+                Some('"') => return error.map_or(Ok(()), Err),
+                Some('\\') => match decode_escape(&self.input[self.pos.offset..]) {
+                    Some((_, len)) => {
+                        let end = self.pos.offset + len;
+                        self.bump_while_before(end);
+                    }
+                    None => {
+                        let found = self.bump().map(String::from).unwrap_or_default();
+                        error.get_or_insert_with(|| {
+                            VMError::ParseError(format!("Invalid escape sequence '\\{}'", found))
+                                .at(Span::new(escape_start, self.pos))
+                        });
+                    }
+                },
+                Some(_) => {}
+            }
+        }
+        Err(VMError::ParseError("Unterminated string literal".to_string())
+            .at(Span::new(start, self.pos)))
+    }
+    
+    /// Lexes one run of whitespace or one comment, if the input continues
+    /// with either. Comments stop before the end of their line.
+    pub fn next_trivia(&mut self) -> Option<Trivia<'a>> {
+        let start = self.pos;
+        let kind = match self.peek()? {
+            '#' => {
+                self.bump_while(|c| c != '\n');
+                TriviaKind::Comment
+            }
+            ch if ch.is_whitespace() => {
+                self.bump_while(char::is_whitespace);
+                TriviaKind::Whitespace
+            }
+            _ => return None,
+        };
+        Some(Trivia {
+            kind,
+            text: &self.input[start.offset..self.pos.offset],
+            span: Span::new(start, self.pos),
+        })
+    }
+    
+    fn skip_trivia(&mut self) {
+        while self.next_trivia().is_some() {}
+    }
+    
+    fn bump_while(&mut self, predicate: impl Fn(char) -> bool) {
+        while self.peek().is_some_and(&predicate) {
+            self.bump();
+        }
+    }
+    
+    fn bump_while_before(&mut self, offset: usize) {
+        while self.pos.offset < offset && self.bump().is_some() {}
+    }
+    
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos.offset..].chars().next()
+    }
+    
+    fn bump(&mut self) -> Option<char> {
+        let ch = self.peek()?;
+        self.pos.advance(ch);
+        Some(ch)
+    }
+}
+
+/// Decodes the escape sequence that follows a backslash in a string literal.
+/// Returns the character and how many bytes of `rest` it spans.
+///
+/// Supported escapes are `\"`, `\\`, `\n`, `\t`, `\r` and `\u{...}` with
+/// one to six hex digits.
+fn decode_escape(rest: &str) -> Option<(char, usize)> {
+    let ch = match rest.chars().next()? {
+        '"' => '"',
+        '\\' => '\\',
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        'u' => {
+            let body = rest.strip_prefix("u{")?;
+            let end = body.find('}')?;
+            let digits = &body[..end];
+            if digits.is_empty() || digits.len() > 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            let ch = char::from_u32(u32::from_str_radix(digits, 16).ok()?)?;
+            return Some((ch, end + 3));
+        }
+        _ => return None,
+    };
+    Some((ch, 1))
+}
+
+/// Decodes the contents of a string literal (without its quotes). Invalid
+/// escapes are rejected by the lexer, so any left here are kept verbatim.
+pub fn unescape(raw: &str) -> String {
+    let mut result = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(index) = rest.find('\\') {
+        result.push_str(&rest[..index]);
+        rest = &rest[index + 1..];
+        match decode_escape(rest) {
+            Some((ch, len)) => {
+                result.push(ch);
+                rest = &rest[len..];
+            }
+            None => result.push('\\'),
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Encodes a value as the contents of a string literal, the inverse of
+/// [`unescape`].
+pub fn escape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            '\r' => result.push_str("\\r"),
+            c if c.is_control() => result.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result
+}
@@ -1,49 +1,43 @@
+pub mod cst;
 pub mod expressions;
+pub mod lexer;
 pub mod parser;
+pub mod printer;
+pub mod span;
 
 use serde::{Deserialize, Serialize};
 
 use crate::utils::error::{VMError, VMResult};
-use self::expressions::{Expression, Atom, Parseable};
+use self::expressions::{Node, Atom, Parseable};
 
-pub use expressions::{Expression as PikoExpression, BinaryOp, Parseable as PikoParseable, Atom as PikoAtom};
+pub use expressions::{Expression as PikoExpression, Node as PikoNode, BinaryOp, Parseable as PikoParseable, Atom as PikoAtom};
+pub use cst::{Cst, CstList, CstNode, CstToken};
 pub use parser::Parser;
+pub use printer::{format_source, PrintOptions, Printer};
+pub use span::{Position, Span, Spanned};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum PikoAst {
-    Expression(Expression),
+    Expression(Node),
     Program(Vec<PikoAst>),
 }
 
 impl Parseable for PikoAst {
     fn parse(input: &str) -> VMResult<Self> {
-        let trimmed = input.trim();
-        
-        if trimmed.is_empty() {
-            return Err(VMError::ParseError("Empty input".to_string()));
-        }
-        
-        let lines: Vec<&str> = trimmed.lines().collect();
-        if lines.len() > 1 {
-            let mut program = Vec::new();
-            for line in lines {
-                if !line.trim().is_empty() {
-                    let expr = Expression::parse(line)?;
-                    program.push(PikoAst::Expression(expr));
-                }
+        match Parser::parse_program(input)? {
+            PikoAst::Program(mut program) if program.len() == 1 => Ok(program.remove(0)),
+            PikoAst::Program(program) if program.is_empty() => {
+                Err(VMError::ParseError("Empty input".to_string()))
             }
-            return Ok(PikoAst::Program(program));
+            ast => Ok(ast),
         }
-        
-        let expr = Expression::parse(trimmed)?;
-        Ok(PikoAst::Expression(expr))
     }
 }
 
 impl Atom for PikoAst {
     fn is_single_letter(&self) -> bool {
         match self {
-            PikoAst::Expression(expr) => expr.is_single_letter(),
+            PikoAst::Expression(expr) => expr.node.is_single_letter(),
             PikoAst::Program(_) => false,
         }
     }
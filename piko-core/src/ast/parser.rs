@@ -1,74 +1,255 @@
-use lexpr::Value;
 use crate::utils::error::{VMError, VMResult};
-use super::expressions::{Expression, BinaryOp, ChainOp};
+use super::PikoAst;
+use super::expressions::{Expression, Node, BinaryOp, ChainOp};
+use super::cst::CstNode;
+use super::lexer::{self, Lexer, Token, TokenKind};
+use super::span::{Span, Spanned};
+
+/// Lists nest no deeper than this. Reading, compiling and evaluating a
+/// form all recurse once per level, so a bound keeps untrusted source
+/// from exhausting the host stack.
+pub const MAX_NESTING: usize = 256;
 
 pub struct Parser;
 
+struct SExpr {
+    kind: SExprKind,
+    span: Span,
+}
+
+enum SExprKind {
+    Ident(String),
+    Operator(String),
+    Str(String),
+    List(Vec<SExpr>),
+}
+
 impl Parser {
-    pub fn parse_expression(input: &str) -> VMResult<Expression> {
-        let cleaned = Self::strip_comments(input);
-        let trimmed = cleaned.trim();
-        
-        if trimmed.is_empty() {
-            return Err(VMError::ParseError("Empty input".to_string()));
+    pub fn parse_expression(input: &str) -> VMResult<Node> {
+        let tokens = Lexer::tokenize(input)?;
+        match Self::group_forms(&tokens)?.as_slice() {
+            [] => Err(VMError::ParseError("Empty input".to_string())),
+            [form] => Self::parse_form(form),
+            [_, extra, ..] => Err(VMError::ParseError("Expected a single expression".to_string())
+                .at(Self::form_span(extra))),
         }
+    }
+    
+    pub fn parse_program(input: &str) -> VMResult<PikoAst> {
+        let tokens = Lexer::tokenize(input)?;
+        let program = Self::group_forms(&tokens)?
+            .into_iter()
+            .map(|form| Self::parse_form(form).map(PikoAst::Expression))
+            .collect::<VMResult<Vec<_>>>()?;
+        Ok(PikoAst::Program(program))
+    }
+    
+    /// Parses every top-level form it can, skipping forms that fail to
+    /// tokenize or parse. Returns the well-formed part of the program with
+    /// one diagnostic per error, ordered by position.
+    pub fn parse_program_recovering(input: &str) -> (PikoAst, Vec<VMError>) {
+        let (tokens, mut errors) = Lexer::tokenize_recovering(input);
+        let invalid: Vec<Span> = errors.iter().filter_map(VMError::span).collect();
+        let mut program = Vec::new();
         
-        if Self::is_string_literal(trimmed) {
-            let content = &trimmed[1..trimmed.len()-1];
-            return Ok(Expression::Literal(content.to_string()));
+        for form in Self::collect_forms(&tokens, &mut errors) {
+            let span = Self::form_span(form);
+            if invalid.iter().any(|bad| span.start.offset <= bad.start.offset && bad.start.offset < span.end.offset) {
+                continue;
+            }
+            match Self::parse_form(form) {
+                Ok(node) => program.push(PikoAst::Expression(node)),
+                Err(error) => errors.push(error),
+            }
         }
         
-        if Self::is_variable(trimmed) {
-            return Ok(Expression::Variable(trimmed.to_string()));
+        errors.sort_by_key(|error| error.span().map(|span| span.start.offset));
+        (PikoAst::Program(program), errors)
+    }
+    
+    pub fn split_forms(input: &str) -> VMResult<Vec<&str>> {
+        let tokens = Lexer::tokenize(input)?;
+        let forms = Self::group_forms(&tokens)?
+            .into_iter()
+            .map(|form| &input[Self::form_span(form).start.offset..Self::form_span(form).end.offset])
+            .collect();
+        Ok(forms)
+    }
+    
+    fn group_forms<'t, 'a>(tokens: &'t [Token<'a>]) -> VMResult<Vec<&'t [Token<'a>]>> {
+        let mut errors = Vec::new();
+        let forms = Self::collect_forms(tokens, &mut errors);
+        match errors.into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(forms),
         }
+    }
+    
+    /// Splits tokens into balanced top-level forms. A stray ')' is reported
+    /// and skipped; an unclosed '(' is reported and reading resumes at the
+    /// next '(' that begins a line.
+    fn collect_forms<'t, 'a>(tokens: &'t [Token<'a>], errors: &mut Vec<VMError>) -> Vec<&'t [Token<'a>]> {
+        let mut forms = Vec::new();
+        let mut i = 0;
         
-        if Self::is_s_expression(trimmed) {
-            let parsed = lexpr::from_str(trimmed)
-                .map_err(|e| VMError::ParseError(format!("Invalid S-expression: {}", e)))?;
-            return Self::parse_sexpr(&parsed);
+        while i < tokens.len() {
+            match tokens[i].kind {
+                TokenKind::RParen => {
+                    errors.push(VMError::ParseError("Unexpected ')'".to_string()).at(tokens[i].span));
+                    i += 1;
+                }
+                TokenKind::LParen => match Self::matching_paren(&tokens[i..]) {
+                    Ok(len) => {
+                        let form = &tokens[i..i + len];
+                        match Self::too_deep(form) {
+                            Some(span) => errors.push(nesting_error(span)),
+                            None => forms.push(form),
+                        }
+                        i += len;
+                    }
+                    Err(missing) => {
+                        errors.push(VMError::ParseError(format!("Unclosed '(': missing {} ')'", missing))
+                            .at(tokens[i].span));
+                        i += 1;
+                        while i < tokens.len() && !Self::starts_line(tokens, i) {
+                            i += 1;
+                        }
+                    }
+                },
+                TokenKind::Str | TokenKind::Ident | TokenKind::Operator => {
+                    forms.push(&tokens[i..=i]);
+                    i += 1;
+                }
+            }
         }
         
-        Ok(Expression::Literal(trimmed.to_string()))
+        forms
     }
     
-    fn is_string_literal(s: &str) -> bool {
-        s.starts_with('"') && s.ends_with('"') && s.len() >= 2
+    fn starts_line(tokens: &[Token], i: usize) -> bool {
+        tokens[i].kind == TokenKind::LParen && tokens[i - 1].span.end.line < tokens[i].span.start.line
     }
     
-    fn is_s_expression(s: &str) -> bool {
-        s.starts_with('(') && s.ends_with(')')
+    /// Returns the length of the list starting at `tokens[0]`, or how many
+    /// closing parens are missing when it never closes.
+    fn matching_paren(tokens: &[Token]) -> Result<usize, usize> {
+        let mut depth = 0;
+        for (i, token) in tokens.iter().enumerate() {
+            match token.kind {
+                TokenKind::LParen => depth += 1,
+                TokenKind::RParen => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(i + 1);
+                    }
+                }
+                TokenKind::Str | TokenKind::Ident | TokenKind::Operator => {}
+            }
+        }
+        Err(depth)
     }
     
-    fn parse_sexpr(value: &Value) -> VMResult<Expression> {
-        match value {
-            Value::String(s) | Value::Symbol(s) => {
-                if Self::is_variable(s) {
-                    Ok(Expression::Variable(s.to_string()))
-                } else {
-                    Ok(Expression::Literal(s.to_string()))
+    /// Returns the first '(' that opens a list nested deeper than
+    /// [`MAX_NESTING`], if any.
+    fn too_deep(tokens: &[Token]) -> Option<Span> {
+        let mut depth = 0;
+        for token in tokens {
+            match token.kind {
+                TokenKind::LParen if depth == MAX_NESTING => return Some(token.span),
+                TokenKind::LParen => depth += 1,
+                TokenKind::RParen => depth -= 1,
+                TokenKind::Str | TokenKind::Ident | TokenKind::Operator => {}
+            }
+        }
+        None
+    }
+    
+    fn form_span(tokens: &[Token]) -> Span {
+        tokens[0].span.to(tokens[tokens.len() - 1].span)
+    }
+    
+    fn parse_form(tokens: &[Token]) -> VMResult<Node> {
+        let sexpr = Self::read_sexpr(&mut tokens.iter())?;
+        Self::parse_sexpr(&sexpr)
+    }
+    
+    fn read_sexpr(tokens: &mut std::slice::Iter<Token>) -> VMResult<SExpr> {
+        let token = tokens.next()
+            .ok_or_else(|| VMError::ParseError("Unexpected end of input".to_string()))?;
+        
+        match token.kind {
+            TokenKind::Ident | TokenKind::Operator | TokenKind::Str => Ok(Self::read_atom(token)),
+            TokenKind::RParen => Err(VMError::ParseError("Unexpected ')'".to_string()).at(token.span)),
+            TokenKind::LParen => {
+                let mut list = Vec::new();
+                loop {
+                    match tokens.as_slice().first() {
+                        Some(close) if close.kind == TokenKind::RParen => {
+                            tokens.next();
+                            return Ok(SExpr { kind: SExprKind::List(list), span: token.span.to(close.span) });
+                        }
+                        Some(_) => list.push(Self::read_sexpr(tokens)?),
+                        None => {
+                            return Err(VMError::ParseError("Unclosed '('".to_string()).at(token.span));
+                        }
+                    }
                 }
             }
-            Value::Cons(cons) => {
-                let list = Self::cons_to_vec(cons)?;
+        }
+    }
+    
+    fn read_atom(token: &Token) -> SExpr {
+        let kind = match token.kind {
+            TokenKind::Str => SExprKind::Str(lexer::unescape(&token.text[1..token.text.len()-1])),
+            TokenKind::Operator => SExprKind::Operator(token.text.to_string()),
+            _ => SExprKind::Ident(token.text.to_string()),
+        };
+        SExpr { kind, span: token.span }
+    }
+    
+    pub fn parse_cst_node(node: &CstNode) -> VMResult<Node> {
+        Self::parse_sexpr(&Self::sexpr_from_cst(node))
+    }
+    
+    fn sexpr_from_cst(node: &CstNode) -> SExpr {
+        match node {
+            CstNode::Token(token) => Self::read_atom(&token.token),
+            CstNode::List(list) => SExpr {
+                kind: SExprKind::List(list.children.iter().map(Self::sexpr_from_cst).collect()),
+                span: node.span(),
+            },
+        }
+    }
+    
+    fn parse_sexpr(sexpr: &SExpr) -> VMResult<Node> {
+        let expr = match &sexpr.kind {
+            SExprKind::Str(s) => Expression::Literal(s.to_string()),
+            SExprKind::Ident(s) => Expression::Variable(s.to_string()),
+            SExprKind::Operator(op) => {
+                return Err(VMError::ParseError(format!(
+                    "Operator '{}' must be the first element of a list", op
+                )).at(sexpr.span));
+            }
+            SExprKind::List(list) => {
                 if list.is_empty() {
-                    return Err(VMError::ParseError("Empty list".to_string()));
+                    return Err(VMError::ParseError("Empty list".to_string()).at(sexpr.span));
                 }
                 
                 let op = Self::extract_operator(&list[0])?;
-                Self::parse_operation(&op, &list)
+                Self::parse_operation(&op, list).map_err(|e| e.at(sexpr.span))?
             }
-            _ => Err(VMError::ParseError("Invalid expression".to_string())),
-        }
+        };
+        Ok(Spanned::new(expr, sexpr.span))
     }
     
-    fn extract_operator(value: &Value) -> VMResult<String> {
-        match value {
-            Value::Symbol(s) => Ok(s.to_string()),
-            _ => Err(VMError::ParseError("First element must be an operator".to_string())),
+    fn extract_operator(value: &SExpr) -> VMResult<String> {
+        match &value.kind {
+            SExprKind::Ident(s) | SExprKind::Operator(s) => Ok(s.to_string()),
+            _ => Err(VMError::ParseError("First element must be an operator".to_string()).at(value.span)),
         }
     }
     
-    fn parse_operation(op: &str, list: &[Value]) -> VMResult<Expression> {
+    fn parse_operation(op: &str, list: &[SExpr]) -> VMResult<Expression> {
         match op {
             "o" => Self::parse_output(list),
             "i" => Self::parse_input(list),
@@ -78,27 +259,22 @@ impl Parser {
             "f" => Self::parse_function(list),
             "l" => Self::parse_loop(list),
             "b" => Self::parse_break(list),
-            "+" => Self::parse_binary_op(list, BinaryOp::Add),
-            "-" => Self::parse_binary_op(list, BinaryOp::Sub),
-            "*" => Self::parse_binary_op(list, BinaryOp::Mul),
-            "/" => Self::parse_binary_op(list, BinaryOp::Div),
-            "<" => Self::parse_binary_op(list, BinaryOp::Lt),
-            ">" => Self::parse_binary_op(list, BinaryOp::Gt),
-            "<=" => Self::parse_binary_op(list, BinaryOp::Le),
-            ">=" => Self::parse_binary_op(list, BinaryOp::Ge),
-            "==" => Self::parse_binary_op(list, BinaryOp::Eq),
-            "!=" => Self::parse_binary_op(list, BinaryOp::Ne),
+            "?" => Self::parse_if(list),
+            "&&" | "||" => Self::parse_logical(op, list),
+            "!" => Self::parse_not(list),
             _ => {
-                if Self::is_chain_op(op) {
+                if let Some(binary_op) = BinaryOp::from_symbol(op) {
+                    Self::parse_binary_op(list, binary_op)
+                } else if Self::is_chain_op(op) {
                     Self::parse_chain_op(list)
                 } else {
-                    Err(VMError::ParseError(format!("Unknown operator: {}", op)))
+                    Err(VMError::ParseError(format!("Unknown operator: {}", op)).at(list[0].span))
                 }
             }
         }
     }
     
-    fn parse_output(list: &[Value]) -> VMResult<Expression> {
+    fn parse_output(list: &[SExpr]) -> VMResult<Expression> {
         if list.len() != 2 {
             return Err(VMError::ParseError("o expects 1 argument".to_string()));
         }
@@ -106,7 +282,7 @@ impl Parser {
         Ok(Expression::Output(Box::new(arg)))
     }
     
-    fn parse_input(list: &[Value]) -> VMResult<Expression> {
+    fn parse_input(list: &[SExpr]) -> VMResult<Expression> {
         if list.len() != 2 {
             return Err(VMError::ParseError("i expects 1 argument".to_string()));
         }
@@ -114,7 +290,7 @@ impl Parser {
         Ok(Expression::Input(var))
     }
     
-    fn parse_assign(list: &[Value]) -> VMResult<Expression> {
+    fn parse_assign(list: &[SExpr]) -> VMResult<Expression> {
         if list.len() != 3 {
             return Err(VMError::ParseError("a expects 2 arguments".to_string()));
         }
@@ -123,7 +299,7 @@ impl Parser {
         Ok(Expression::Assign(var, Box::new(expr)))
     }
     
-    fn parse_return(list: &[Value]) -> VMResult<Expression> {
+    fn parse_return(list: &[SExpr]) -> VMResult<Expression> {
         if list.len() != 2 {
             return Err(VMError::ParseError("r expects 1 argument".to_string()));
         }
@@ -131,33 +307,42 @@ impl Parser {
         Ok(Expression::Return(Box::new(expr)))
     }
     
-    fn parse_call(list: &[Value]) -> VMResult<Expression> {
+    fn parse_call(list: &[SExpr]) -> VMResult<Expression> {
         if list.len() < 2 {
             return Err(VMError::ParseError("c expects at least 1 argument".to_string()));
         }
         let func_name = Self::extract_symbol(&list[1], "c expects a function name")?;
-        let mut args = Vec::new();
-        for i in 2..list.len() {
-            args.push(Self::parse_sexpr(&list[i])?);
-        }
+        let args = list[2..].iter()
+            .map(Self::parse_sexpr)
+            .collect::<VMResult<Vec<_>>>()?;
         Ok(Expression::Call(func_name, args))
     }
     
-    fn parse_function(list: &[Value]) -> VMResult<Expression> {
+    /// Functions take either bare parameters and a single body,
+    /// `(f name x y body)`, or a parenthesized parameter list followed by
+    /// one or more body forms, `(f name (x y) first second)`.
+    fn parse_function(list: &[SExpr]) -> VMResult<Expression> {
         if list.len() < 4 {
             return Err(VMError::ParseError("f expects at least 3 arguments".to_string()));
         }
         let func_name = Self::extract_symbol(&list[1], "f expects a function name")?;
-        let mut params = Vec::new();
-        for i in 2..list.len()-1 {
-            let param = Self::extract_symbol(&list[i], "f expects parameter names")?;
-            params.push(param);
+        
+        if let SExprKind::List(param_list) = &list[2].kind {
+            let params = param_list.iter()
+                .map(|param| Self::extract_symbol(param, "f expects parameter names"))
+                .collect::<VMResult<Vec<_>>>()?;
+            let body = Self::parse_body(&list[3..])?;
+            return Ok(Expression::Function(func_name, params, Box::new(body)));
         }
+        
+        let params = list[2..list.len()-1].iter()
+            .map(|param| Self::extract_symbol(param, "f expects parameter names"))
+            .collect::<VMResult<Vec<_>>>()?;
         let body = Self::parse_sexpr(&list[list.len()-1])?;
         Ok(Expression::Function(func_name, params, Box::new(body)))
     }
     
-    fn parse_loop(list: &[Value]) -> VMResult<Expression> {
+    fn parse_loop(list: &[SExpr]) -> VMResult<Expression> {
         if list.len() < 2 {
             return Err(VMError::ParseError("l expects at least 1 argument".to_string()));
         }
@@ -166,34 +351,66 @@ impl Parser {
             Ok(Expression::Loop(None, Box::new(body)))
         } else {
             let condition = Self::parse_sexpr(&list[1])?;
-            let mut body_exprs = Vec::new();
-            for i in 2..list.len() {
-                body_exprs.push(Self::parse_sexpr(&list[i])?);
-            }
-            let body = if body_exprs.len() == 1 {
-                body_exprs.into_iter().next().unwrap()
-            } else {
-                Expression::Block(body_exprs)
-            };
+            let body = Self::parse_body(&list[2..])?;
             Ok(Expression::Loop(Some(Box::new(condition)), Box::new(body)))
         }
     }
     
-    fn parse_break(list: &[Value]) -> VMResult<Expression> {
+    /// Parses a non-empty sequence of body forms, wrapping several forms in
+    /// an implicit block.
+    fn parse_body(forms: &[SExpr]) -> VMResult<Node> {
+        let mut body_exprs = forms.iter()
+            .map(Self::parse_sexpr)
+            .collect::<VMResult<Vec<_>>>()?;
+        if body_exprs.len() == 1 {
+            return Ok(body_exprs.remove(0));
+        }
+        let span = body_exprs[0].span.to(body_exprs[body_exprs.len()-1].span);
+        Ok(Spanned::new(Expression::Block(body_exprs), span))
+    }
+    
+    fn parse_if(list: &[SExpr]) -> VMResult<Expression> {
+        if list.len() != 3 && list.len() != 4 {
+            return Err(VMError::ParseError("? expects a condition, a then-branch and an optional else-branch".to_string()));
+        }
+        let condition = Self::parse_sexpr(&list[1])?;
+        let then_branch = Self::parse_sexpr(&list[2])?;
+        let else_branch = list.get(3).map(Self::parse_sexpr).transpose()?;
+        Ok(Expression::If(Box::new(condition), Box::new(then_branch), else_branch.map(Box::new)))
+    }
+    
+    fn parse_logical(op: &str, list: &[SExpr]) -> VMResult<Expression> {
+        if list.len() != 3 {
+            return Err(VMError::ParseError(format!("{} expects 2 arguments", op)));
+        }
+        let left = Box::new(Self::parse_sexpr(&list[1])?);
+        let right = Box::new(Self::parse_sexpr(&list[2])?);
+        Ok(if op == "&&" { Expression::And(left, right) } else { Expression::Or(left, right) })
+    }
+    
+    fn parse_not(list: &[SExpr]) -> VMResult<Expression> {
+        if list.len() != 2 {
+            return Err(VMError::ParseError("! expects 1 argument".to_string()));
+        }
+        let arg = Self::parse_sexpr(&list[1])?;
+        Ok(Expression::Not(Box::new(arg)))
+    }
+    
+    fn parse_break(list: &[SExpr]) -> VMResult<Expression> {
         if list.len() != 1 {
             return Err(VMError::ParseError("b expects no arguments".to_string()));
         }
         Ok(Expression::Break)
     }
     
-    fn extract_symbol(value: &Value, error_msg: &str) -> VMResult<String> {
-        match value {
-            Value::Symbol(s) => Ok(s.to_string()),
-            _ => Err(VMError::ParseError(error_msg.to_string())),
+    fn extract_symbol(value: &SExpr, error_msg: &str) -> VMResult<String> {
+        match &value.kind {
+            SExprKind::Ident(s) => Ok(s.to_string()),
+            _ => Err(VMError::ParseError(error_msg.to_string()).at(value.span)),
         }
     }
     
-    fn parse_binary_op(list: &[Value], op: BinaryOp) -> VMResult<Expression> {
+    fn parse_binary_op(list: &[SExpr], op: BinaryOp) -> VMResult<Expression> {
         if list.len() != 3 {
             return Err(VMError::ParseError("Binary operator expects 2 arguments".to_string()));
         }
@@ -206,7 +423,7 @@ impl Parser {
         op.len() > 1 && op.chars().all(|c| matches!(c, 'o' | 'i' | 'a' | 'r' | 'c' | 'f' | 'l' | 'b'))
     }
     
-    fn parse_chain_op(list: &[Value]) -> VMResult<Expression> {
+    fn parse_chain_op(list: &[SExpr]) -> VMResult<Expression> {
         let chain_str = Self::extract_operator(&list[0])?;
         let mut ops = Vec::new();
         let mut arg_index = 1;
@@ -240,59 +457,14 @@ impl Parser {
                     arg_index += 1;
                 }
                 'b' => ops.push(ChainOp::Break),
-                _ => return Err(VMError::ParseError(format!("Invalid chain operator: {}", c))),
+                _ => return Err(VMError::ParseError(format!("Invalid chain operator: {}", c)).at(list[0].span)),
             }
         }
         
         Ok(Expression::ChainedOp(ops))
     }
-    
-    fn cons_to_vec(cons: &lexpr::Cons) -> VMResult<Vec<Value>> {
-        let mut result = Vec::new();
-        let mut current = cons;
-        
-        loop {
-            result.push(current.car().clone());
-            match current.cdr() {
-                Value::Cons(next_cons) => current = next_cons,
-                Value::Nil | Value::Null => break,
-                _ => return Err(VMError::ParseError("Improper list".to_string())),
-            }
-        }
-        
-        Ok(result)
-    }
-    
-    fn is_variable(s: &str) -> bool {
-        !s.is_empty() && s.chars().all(|c| c.is_ascii_lowercase())
-    }
-    
-    fn strip_comments(input: &str) -> String {
-        let mut result = String::new();
-        let mut in_string = false;
-        let mut escape_next = false;
-        
-        for ch in input.chars() {
-            if escape_next {
-                result.push(ch);
-                escape_next = false;
-                continue;
-            }
-            
-            match ch {
-                '\\' if in_string => {
-                    escape_next = true;
-                    result.push(ch);
-                }
-                '"' => {
-                    in_string = !in_string;
-                    result.push(ch);
-                }
-                '#' if !in_string => break,
-                _ => result.push(ch),
-            }
-        }
-        
-        result
-    }
-}
\ No newline at end of file
+}
+
+pub(super) fn nesting_error(span: Span) -> VMError {
+    VMError::ParseError(format!("Lists may nest at most {} deep", MAX_NESTING)).at(span)
+}
@@ -0,0 +1,420 @@
+use std::fmt;
+
+use crate::utils::error::VMResult;
+use super::PikoAst;
+use super::expressions::{ChainOp, Expression, Node};
+use super::cst::{Cst, CstNode};
+use super::lexer::{self, Trivia, TriviaKind};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrintOptions {
+    pub indent: usize,
+    pub width: usize,
+}
+
+impl Default for PrintOptions {
+    fn default() -> Self {
+        PrintOptions { indent: 4, width: 80 }
+    }
+}
+
+/// One element of a list form: either a bare word (operator, variable or
+/// function name) or a nested expression.
+enum Item<'a> {
+    Word(String),
+    Node(&'a Node),
+}
+
+/// A list form split into the part that stays on the opening line and the
+/// part that moves to indented lines when the form is too wide.
+struct Form<'a> {
+    head: Vec<Item<'a>>,
+    body: Vec<Item<'a>>,
+}
+
+pub struct Printer {
+    options: PrintOptions,
+}
+
+impl Printer {
+    pub fn new(options: PrintOptions) -> Self {
+        Printer { options }
+    }
+    
+    pub fn print_ast(&self, ast: &PikoAst) -> String {
+        match ast {
+            PikoAst::Expression(node) => self.print_node(node),
+            PikoAst::Program(forms) => forms.iter()
+                .map(|form| self.print_ast(form))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+    
+    pub fn print_node(&self, node: &Node) -> String {
+        self.print_expression(&node.node)
+    }
+    
+    pub fn print_expression(&self, expr: &Expression) -> String {
+        self.layout(expr, 0)
+    }
+    
+    fn layout(&self, expr: &Expression, column: usize) -> String {
+        if let Expression::Block(exprs) = expr {
+            let separator = format!("\n{}", " ".repeat(column));
+            return exprs.iter()
+                .map(|expr| self.layout(&expr.node, column))
+                .collect::<Vec<_>>()
+                .join(&separator);
+        }
+        
+        let flat = Self::flat(expr);
+        let form = match Self::form(expr) {
+            Some(form) if column + flat.len() > self.options.width => form,
+            _ => return flat,
+        };
+        
+        let mut out = String::from("(");
+        let head = form.head.iter().map(Self::flat_item).collect::<Vec<_>>().join(" ");
+        out.push_str(&head);
+        
+        let body_column = column + self.options.indent;
+        for item in &form.body {
+            out.push('\n');
+            out.push_str(&" ".repeat(body_column));
+            match item {
+                Item::Word(word) => out.push_str(word),
+                Item::Node(node) => out.push_str(&self.layout(&node.node, body_column)),
+            }
+        }
+        out.push(')');
+        out
+    }
+    
+    /// Lays out a node of a concrete syntax tree along with the expression
+    /// parsed from it. Nodes without comments print exactly as their
+    /// expression would; a list with comments inside is always broken over
+    /// lines, with each comment kept before the item it precedes or at the
+    /// end of the line it followed. A list whose items do not line up with
+    /// its expression's is printed as written rather than lose comments.
+    fn layout_cst(&self, node: &CstNode, expr: &Expression, column: usize) -> String {
+        let (list, form) = match (node, Self::cst_form(node, expr)) {
+            (CstNode::List(list), Some(form)) if Self::has_comments(node) => (list, form),
+            _ => return self.layout(expr, column),
+        };
+        let head_len = form.head.len();
+        if list.children.len() != head_len + form.body.len() {
+            let leading = node.leading().iter().map(|trivia| trivia.text.len()).sum::<usize>();
+            return node.to_string().split_off(leading);
+        }
+        
+        // The head stays on the opening line, so comments inside it have
+        // nowhere to go but above the form.
+        let mut out = String::new();
+        for child in &list.children[..head_len] {
+            for comment in Self::all_comments(child) {
+                out.push_str(comment);
+                Self::new_line(&mut out, column, false);
+            }
+        }
+        
+        out.push('(');
+        let head = form.head.iter().map(Self::flat_item).collect::<Vec<_>>().join(" ");
+        out.push_str(&head);
+        
+        let body_column = column + self.options.indent;
+        for (child, item) in list.children[head_len..].iter().zip(&form.body) {
+            Self::write_comments(&mut out, child.leading(), body_column, false);
+            Self::new_line(&mut out, body_column, false);
+            match item {
+                Item::Word(word) => out.push_str(word),
+                Item::Node(node) => out.push_str(&self.layout_cst(child, &node.node, body_column)),
+            }
+        }
+        
+        if list.close.leading.iter().any(|trivia| trivia.kind == TriviaKind::Comment) {
+            Self::write_comments(&mut out, &list.close.leading, body_column, false);
+            Self::new_line(&mut out, column, false);
+        }
+        out.push(')');
+        out
+    }
+    
+    /// The form of `expr`, keeping a function's parenthesized parameter list
+    /// when the source wrote one, so each item has a node of its own.
+    fn cst_form<'a>(node: &CstNode, expr: &'a Expression) -> Option<Form<'a>> {
+        match (node, expr) {
+            (CstNode::List(list), Expression::Function(name, params, body))
+                if matches!(list.children.get(2), Some(CstNode::List(_))) =>
+            {
+                Some(Self::function_form(name, params, body, true))
+            }
+            _ => Self::form(expr),
+        }
+    }
+    
+    /// Whether any comment lies inside `node`, not counting those before it.
+    fn has_comments(node: &CstNode) -> bool {
+        match node {
+            CstNode::Token(_) => false,
+            CstNode::List(list) => {
+                list.children.iter().any(|child| child.comments().next().is_some() || Self::has_comments(child))
+                    || list.close.leading.iter().any(|trivia| trivia.kind == TriviaKind::Comment)
+            }
+        }
+    }
+    
+    /// Every comment before and inside `node`, in source order.
+    fn all_comments<'a>(node: &'a CstNode) -> Vec<&'a str> {
+        let mut comments: Vec<&str> = node.comments().map(|comment| comment.text).collect();
+        if let CstNode::List(list) = node {
+            for child in &list.children {
+                comments.extend(Self::all_comments(child));
+            }
+            comments.extend(list.close.leading.iter()
+                .filter(|trivia| trivia.kind == TriviaKind::Comment)
+                .map(|comment| comment.text));
+        }
+        comments
+    }
+    
+    /// Writes the comments among `trivia`: one that shared a line with what
+    /// came before stays at the end of that line, the others get lines of
+    /// their own at `column`, after a blank line if the source had one there
+    /// and `blank_lines` is set. Returns how many line breaks followed the
+    /// last comment.
+    fn write_comments(out: &mut String, trivia: &[Trivia], column: usize, blank_lines: bool) -> usize {
+        let mut newlines = 0;
+        for trivia in trivia {
+            match trivia.kind {
+                TriviaKind::Whitespace => newlines += trivia.text.matches('\n').count(),
+                TriviaKind::Comment => {
+                    if newlines == 0 && !out.is_empty() {
+                        out.push_str("  ");
+                    } else {
+                        Self::new_line(out, column, blank_lines && newlines > 1);
+                    }
+                    out.push_str(trivia.text);
+                    newlines = 0;
+                }
+            }
+        }
+        newlines
+    }
+    
+    /// Starts a line at `column`, after a blank one if `blank`. Nothing is
+    /// written at the very start of the output.
+    fn new_line(out: &mut String, column: usize, blank: bool) {
+        if out.is_empty() {
+            return;
+        }
+        if blank {
+            out.push('\n');
+        }
+        out.push('\n');
+        out.push_str(&" ".repeat(column));
+    }
+    
+    fn flat(expr: &Expression) -> String {
+        match expr {
+            Expression::Variable(name) => name.clone(),
+            Expression::Literal(value) => format!("\"{}\"", lexer::escape(value)),
+            Expression::Block(exprs) => exprs.iter()
+                .map(|expr| Self::flat(&expr.node))
+                .collect::<Vec<_>>()
+                .join(" "),
+            _ => match Self::form(expr) {
+                Some(form) => {
+                    let items = form.head.iter().chain(form.body.iter())
+                        .map(Self::flat_item)
+                        .collect::<Vec<_>>();
+                    format!("({})", items.join(" "))
+                }
+                None => String::new(),
+            },
+        }
+    }
+    
+    fn flat_item(item: &Item) -> String {
+        match item {
+            Item::Word(word) => word.clone(),
+            Item::Node(node) => Self::flat(&node.node),
+        }
+    }
+    
+    fn form(expr: &Expression) -> Option<Form<'_>> {
+        let word = |s: &str| Item::Word(s.to_string());
+        
+        let form = match expr {
+            Expression::Variable(_) | Expression::Literal(_) | Expression::Block(_) => return None,
+            Expression::BinaryOp(left, op, right) => Form {
+                head: vec![word(op.symbol())],
+                body: vec![Item::Node(left), Item::Node(right)],
+            },
+            Expression::Output(arg) => Form {
+                head: vec![word("o")],
+                body: vec![Item::Node(arg)],
+            },
+            Expression::Input(var) => Form {
+                head: vec![word("i"), word(var)],
+                body: vec![],
+            },
+            Expression::Assign(var, value) => Form {
+                head: vec![word("a"), word(var)],
+                body: vec![Item::Node(value)],
+            },
+            Expression::Return(value) => Form {
+                head: vec![word("r")],
+                body: vec![Item::Node(value)],
+            },
+            Expression::Call(name, args) => Form {
+                head: vec![word("c"), word(name)],
+                body: args.iter().map(Item::Node).collect(),
+            },
+            Expression::Function(name, params, body) => Self::function_form(name, params, body, false),
+            Expression::Loop(condition, body) => Self::loop_form(condition.as_deref(), body),
+            Expression::If(condition, then_branch, else_branch) => {
+                let mut body = vec![Item::Node(then_branch)];
+                body.extend(else_branch.as_deref().map(Item::Node));
+                Form { head: vec![word("?"), Item::Node(condition)], body }
+            }
+            Expression::And(left, right) => Form {
+                head: vec![word("&&")],
+                body: vec![Item::Node(left), Item::Node(right)],
+            },
+            Expression::Or(left, right) => Form {
+                head: vec![word("||")],
+                body: vec![Item::Node(left), Item::Node(right)],
+            },
+            Expression::Not(arg) => Form {
+                head: vec![word("!")],
+                body: vec![Item::Node(arg)],
+            },
+            Expression::Break => Form {
+                head: vec![word("b")],
+                body: vec![],
+            },
+            Expression::ChainedOp(ops) => Self::chain_form(ops),
+        };
+        Some(form)
+    }
+    
+    /// Parameters are written bare unless `parenthesized` is set, or the
+    /// function has none or several body forms and so needs the list.
+    fn function_form<'a>(name: &str, params: &[String], body: &'a Node, parenthesized: bool) -> Form<'a> {
+        let mut head = vec![Item::Word("f".to_string()), Item::Word(name.to_string())];
+        match &body.node {
+            Expression::Block(exprs) => {
+                head.push(Item::Word(format!("({})", params.join(" "))));
+                Form { head, body: exprs.iter().map(Item::Node).collect() }
+            }
+            _ if parenthesized || params.is_empty() => {
+                head.push(Item::Word(format!("({})", params.join(" "))));
+                Form { head, body: vec![Item::Node(body)] }
+            }
+            _ => {
+                head.extend(params.iter().map(|param| Item::Word(param.clone())));
+                Form { head, body: vec![Item::Node(body)] }
+            }
+        }
+    }
+    
+    fn loop_form<'a>(condition: Option<&'a Node>, body: &'a Node) -> Form<'a> {
+        let mut head = vec![Item::Word("l".to_string())];
+        head.extend(condition.map(Item::Node));
+        let body = match &body.node {
+            Expression::Block(exprs) if condition.is_some() => exprs.iter().map(Item::Node).collect(),
+            _ => vec![Item::Node(body)],
+        };
+        Form { head, body }
+    }
+    
+    fn chain_form(ops: &[ChainOp]) -> Form<'_> {
+        let mut letters = String::new();
+        let mut args = Vec::new();
+        
+        for op in ops {
+            match op {
+                ChainOp::Input(var) => {
+                    letters.push('i');
+                    args.push(Item::Word(var.clone()));
+                }
+                ChainOp::Output => letters.push('o'),
+                ChainOp::Assign(var, value) => {
+                    letters.push('a');
+                    args.push(Item::Word(var.clone()));
+                    args.push(Item::Node(value));
+                }
+                ChainOp::Return(value) => {
+                    letters.push('r');
+                    args.push(Item::Node(value));
+                }
+                ChainOp::Call(name, call_args) => {
+                    letters.push('c');
+                    args.push(Item::Word(name.clone()));
+                    args.extend(call_args.iter().map(Item::Node));
+                }
+                ChainOp::Function(name, params, body) => {
+                    letters.push('f');
+                    args.push(Item::Word(name.clone()));
+                    args.extend(params.iter().map(|param| Item::Word(param.clone())));
+                    args.push(Item::Node(body));
+                }
+                ChainOp::Loop(condition, body) => {
+                    letters.push('l');
+                    args.extend(condition.as_deref().map(Item::Node));
+                    args.push(Item::Node(body));
+                }
+                ChainOp::Break => letters.push('b'),
+            }
+        }
+        
+        let split = args.iter().position(|item| matches!(item, Item::Node(_))).unwrap_or(args.len());
+        let body = args.split_off(split);
+        let mut head = vec![Item::Word(letters)];
+        head.extend(args);
+        Form { head, body }
+    }
+}
+
+/// Reformats a whole Piko source file into canonical layout, one top-level
+/// form per line. Comments are kept, and so is one blank line wherever the
+/// source had blank lines between top-level forms or comments.
+pub fn format_source(source: &str, options: &PrintOptions) -> VMResult<String> {
+    let printer = Printer::new(options.clone());
+    let cst = Cst::parse(source)?;
+    let mut formatted = String::new();
+    for form in &cst.forms {
+        let node = form.to_node()?;
+        let newlines = Printer::write_comments(&mut formatted, form.leading(), 0, true);
+        Printer::new_line(&mut formatted, 0, newlines > 1);
+        formatted.push_str(&printer.layout_cst(form, &node.node, 0));
+    }
+    Printer::write_comments(&mut formatted, &cst.trailing, 0, true);
+    if !formatted.is_empty() {
+        formatted.push('\n');
+    }
+    Ok(formatted)
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&Printer::new(PrintOptions::default()).print_expression(self))
+    }
+}
+
+impl fmt::Display for ChainOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let form = Printer::chain_form(std::slice::from_ref(self));
+        let items = form.head.iter().chain(form.body.iter())
+            .map(Printer::flat_item)
+            .collect::<Vec<_>>();
+        f.write_str(&items.join(" "))
+    }
+}
+
+impl fmt::Display for PikoAst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&Printer::new(PrintOptions::default()).print_ast(self))
+    }
+}
@@ -4,5 +4,5 @@ pub mod vm;
 
 pub use ast::PikoAst;
 pub use ast::expressions::Parseable;
-pub use utils::{VMError, VMResult};
+pub use utils::{Quota, VMError, VMResult};
 
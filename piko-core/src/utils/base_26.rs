@@ -1,34 +1,96 @@
+use super::bignum::BigUint;
+
 pub fn add(left: &str, right: &str) -> String {
+    if let Some((l, r)) = to_small_pair(left, right) {
+        return from_small(l + r);
+    }
     let left_num = to_num(left);
     let right_num = to_num(right);
-    from_num(left_num.saturating_add(right_num))
+    from_num(left_num.add(&right_num))
 }
 
 pub fn sub(left: &str, right: &str) -> String {
+    if let Some((l, r)) = to_small_pair(left, right) {
+        return from_small(l.saturating_sub(r).max(1));
+    }
     let left_num = to_num(left);
     let right_num = to_num(right);
-    from_num(left_num.saturating_sub(right_num).max(1))
+    from_num(left_num.saturating_sub(&right_num).max(BigUint::from_u32(1)))
 }
 
 pub fn mul(left: &str, right: &str) -> String {
+    if let Some(product) = to_small_pair(left, right).and_then(|(l, r)| l.checked_mul(r)) {
+        return from_small(product);
+    }
     let left_num = to_num(left);
     let right_num = to_num(right);
-    from_num(left_num.saturating_mul(right_num))
+    from_num(left_num.mul(&right_num))
 }
 
 pub fn div(left: &str, right: &str) -> String {
+    if let Some((l, r)) = to_small_pair(left, right) {
+        return match l.checked_div(r) {
+            Some(quotient) => from_small(quotient.max(1)),
+            None => "a".to_string(),
+        };
+    }
     let left_num = to_num(left);
     let right_num = to_num(right);
-    if right_num == 0 {
-        return "a".to_string();
+    match left_num.div_rem(&right_num) {
+        Some((quotient, _)) => from_num(quotient.max(BigUint::from_u32(1))),
+        None => "a".to_string(),
+    }
+}
+
+/// Remainder in bijective form: the result lies in `a..=right`, with `right`
+/// itself standing in for a remainder of zero. `(% x "z")` is therefore the
+/// last digit of `x`, and `(% x "b")` is "a" for odd and "b" for even values.
+pub fn rem(left: &str, right: &str) -> String {
+    if let Some((l, r)) = to_small_pair(left, right) {
+        return match l.saturating_sub(1).checked_rem(r) {
+            Some(remainder) => from_small(remainder + 1),
+            None => "a".to_string(),
+        };
+    }
+    let left_num = to_num(left);
+    let right_num = to_num(right);
+    let one = BigUint::from_u32(1);
+    match left_num.saturating_sub(&one).div_rem(&right_num) {
+        Some((_, remainder)) => from_num(remainder.add(&one)),
+        None => "a".to_string(),
     }
-    from_num((left_num / right_num).max(1))
+}
+
+pub fn pow(left: &str, right: &str) -> String {
+    pow_within(left, right, usize::MAX).unwrap_or_default()
+}
+
+/// Like [`pow`], but gives up with `None` as soon as the result is certain
+/// to be longer than `max_len` letters, before spending time and memory on
+/// computing it in full.
+pub fn pow_within(left: &str, right: &str, max_len: usize) -> Option<String> {
+    let left_num = to_num(left);
+    let right_num = to_num(right);
+    // A value of n letters is below 27^n < 2^(5n).
+    let max_bits = max_len.saturating_mul(5);
+    left_num.pow(&right_num, max_bits).map(from_num)
+}
+
+pub fn min<'a>(left: &'a str, right: &'a str) -> &'a str {
+    if compare_le(left, right) { left } else { right }
+}
+
+pub fn max<'a>(left: &'a str, right: &'a str) -> &'a str {
+    if compare_ge(left, right) { left } else { right }
 }
 
 macro_rules! compare_op {
     ($name:ident, $op:tt) => {
         pub fn $name(left: &str, right: &str) -> bool {
-            to_num(left) $op to_num(right)
+            match to_small_pair(left, right) {
+                Some((l, r)) => l $op r,
+                None => to_num(left) $op to_num(right),
+            }
         }
     };
 }
@@ -40,24 +102,88 @@ compare_op!(compare_gt, >);
 compare_op!(compare_le, <=);
 compare_op!(compare_ge, >=);
 
-fn to_num(s: &str) -> u64 {
-    s.chars()
-        .map(|c| c as u64 - b'a' as u64 + 1)
-        .fold(0, |acc, digit| acc * 26 + digit)
+/// Returns the first character of `value` that is not a base-26 digit
+/// (`a`-`z`), if any.
+pub fn invalid_digit(value: &str) -> Option<char> {
+    value.chars().find(|c| !c.is_ascii_lowercase())
 }
 
-fn from_num(num: u64) -> String {
+/// Converts a count or position to base-26, with zero as the empty string.
+pub fn from_index(mut index: usize) -> String {
+    let mut result = String::new();
+    while index > 0 {
+        index -= 1;
+        result.push(char::from(b'a' + (index % 26) as u8));
+        index /= 26;
+    }
+    result.chars().rev().collect()
+}
+
+/// Converts a base-26 value to a count or position, or `None` if it does
+/// not fit in a `usize`.
+pub fn to_index(value: &str) -> Option<usize> {
+    let mut index = 0usize;
+    for c in value.chars().filter(char::is_ascii_lowercase) {
+        index = index.checked_mul(26)?.checked_add(c as usize - 'a' as usize + 1)?;
+    }
+    Some(index)
+}
+
+/// Values of up to this many letters fit in a `u64` with room to add two of
+/// them, so most arithmetic in real programs never needs a `BigUint`.
+const SMALL_LEN: usize = 13;
+
+fn to_small_pair(left: &str, right: &str) -> Option<(u64, u64)> {
+    Some((to_small(left)?, to_small(right)?))
+}
+
+/// Like [`to_num`], for short values made only of `a`-`z`.
+fn to_small(s: &str) -> Option<u64> {
+    if s.len() > SMALL_LEN {
+        return None;
+    }
+    s.bytes().try_fold(0u64, |num, c| {
+        c.is_ascii_lowercase().then(|| num * 26 + (c - b'a' + 1) as u64)
+    })
+}
+
+fn from_small(mut num: u64) -> String {
     if num == 0 {
         return "a".to_string();
     }
     
+    let mut digits = Vec::new();
+    while num > 0 {
+        num -= 1;
+        digits.push(b'a' + (num % 26) as u8);
+        num /= 26;
+    }
+    digits.reverse();
+    String::from_utf8(digits).expect("digits are ASCII")
+}
+
+/// Characters outside `a`-`z` carry no digit value and are skipped; callers
+/// that need to reject them check with [`invalid_digit`] first.
+fn to_num(s: &str) -> BigUint {
+    let mut num = BigUint::zero();
+    for c in s.chars().filter(char::is_ascii_lowercase) {
+        num.mul_add_small(26, c as u32 - 'a' as u32 + 1);
+    }
+    num
+}
+
+fn from_num(num: BigUint) -> String {
+    if num.is_zero() {
+        return "a".to_string();
+    }
+    
     let mut result = String::new();
     let mut n = num;
+    let one = BigUint::from_u32(1);
     
-    while n > 0 {
-        n -= 1;
-        result.push(char::from(b'a' + (n % 26) as u8));
-        n /= 26;
+    while !n.is_zero() {
+        n = n.saturating_sub(&one);
+        result.push(char::from(b'a' + n.div_rem_small(26) as u8));
     }
     
     result.chars().rev().collect()
@@ -0,0 +1,191 @@
+use std::cmp::Ordering;
+
+/// Arbitrary-precision natural number backing base-26 arithmetic.
+/// Limbs are little-endian with no trailing zero limbs, so zero is empty.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BigUint {
+    limbs: Vec<u32>,
+}
+
+impl BigUint {
+    pub fn zero() -> Self {
+        BigUint { limbs: Vec::new() }
+    }
+    
+    pub fn from_u32(value: u32) -> Self {
+        let mut result = BigUint { limbs: vec![value] };
+        result.normalize();
+        result
+    }
+    
+    pub fn is_zero(&self) -> bool {
+        self.limbs.is_empty()
+    }
+    
+    fn normalize(&mut self) {
+        while self.limbs.last() == Some(&0) {
+            self.limbs.pop();
+        }
+    }
+    
+    /// Computes `self * factor + addend` in place.
+    pub fn mul_add_small(&mut self, factor: u32, addend: u32) {
+        let mut carry = addend as u64;
+        for limb in self.limbs.iter_mut() {
+            let value = *limb as u64 * factor as u64 + carry;
+            *limb = value as u32;
+            carry = value >> 32;
+        }
+        if carry > 0 {
+            self.limbs.push(carry as u32);
+        }
+        self.normalize();
+    }
+    
+    /// Divides in place, returning the remainder.
+    pub fn div_rem_small(&mut self, divisor: u32) -> u32 {
+        let mut remainder = 0u64;
+        for limb in self.limbs.iter_mut().rev() {
+            let value = (remainder << 32) | *limb as u64;
+            *limb = (value / divisor as u64) as u32;
+            remainder = value % divisor as u64;
+        }
+        self.normalize();
+        remainder as u32
+    }
+    
+    pub fn add(&self, other: &BigUint) -> BigUint {
+        let (long, short) = if self.limbs.len() >= other.limbs.len() { (self, other) } else { (other, self) };
+        let mut limbs = Vec::with_capacity(long.limbs.len() + 1);
+        let mut carry = 0u64;
+        for (i, limb) in long.limbs.iter().enumerate() {
+            let value = *limb as u64 + *short.limbs.get(i).unwrap_or(&0) as u64 + carry;
+            limbs.push(value as u32);
+            carry = value >> 32;
+        }
+        if carry > 0 {
+            limbs.push(carry as u32);
+        }
+        BigUint { limbs }
+    }
+    
+    /// Subtracts `other`, saturating at zero.
+    pub fn saturating_sub(&self, other: &BigUint) -> BigUint {
+        if *self <= *other {
+            return BigUint::zero();
+        }
+        let mut limbs = Vec::with_capacity(self.limbs.len());
+        let mut borrow = 0i64;
+        for (i, limb) in self.limbs.iter().enumerate() {
+            let mut value = *limb as i64 - *other.limbs.get(i).unwrap_or(&0) as i64 - borrow;
+            borrow = 0;
+            if value < 0 {
+                value += 1 << 32;
+                borrow = 1;
+            }
+            limbs.push(value as u32);
+        }
+        let mut result = BigUint { limbs };
+        result.normalize();
+        result
+    }
+    
+    pub fn mul(&self, other: &BigUint) -> BigUint {
+        if self.is_zero() || other.is_zero() {
+            return BigUint::zero();
+        }
+        let mut limbs = vec![0u32; self.limbs.len() + other.limbs.len()];
+        for (i, a) in self.limbs.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, b) in other.limbs.iter().enumerate() {
+                let value = limbs[i + j] as u64 + *a as u64 * *b as u64 + carry;
+                limbs[i + j] = value as u32;
+                carry = value >> 32;
+            }
+            limbs[i + other.limbs.len()] = carry as u32;
+        }
+        let mut result = BigUint { limbs };
+        result.normalize();
+        result
+    }
+    
+    /// Returns the quotient and remainder, or `None` when dividing by zero.
+    pub fn div_rem(&self, divisor: &BigUint) -> Option<(BigUint, BigUint)> {
+        if divisor.is_zero() {
+            return None;
+        }
+        if divisor.limbs.len() == 1 {
+            let mut quotient = self.clone();
+            let remainder = quotient.div_rem_small(divisor.limbs[0]);
+            return Some((quotient, BigUint::from_u32(remainder)));
+        }
+        if self < divisor {
+            return Some((BigUint::zero(), self.clone()));
+        }
+        
+        let mut quotient = vec![0u32; self.limbs.len()];
+        let mut remainder = BigUint::zero();
+        for bit in (0..self.bits()).rev() {
+            remainder.shift_left_one(self.bit(bit));
+            if remainder >= *divisor {
+                remainder = remainder.saturating_sub(divisor);
+                quotient[bit / 32] |= 1 << (bit % 32);
+            }
+        }
+        let mut quotient = BigUint { limbs: quotient };
+        quotient.normalize();
+        Some((quotient, remainder))
+    }
+    
+    /// Raises to a power, giving up with `None` once the result would
+    /// need more than `max_bits` bits.
+    pub fn pow(&self, exponent: &BigUint, max_bits: usize) -> Option<BigUint> {
+        let mut result = BigUint::from_u32(1);
+        for bit in (0..exponent.bits()).rev() {
+            result = result.mul(&result);
+            if exponent.bit(bit) {
+                result = result.mul(self);
+            }
+            if result.bits() > max_bits {
+                return None;
+            }
+        }
+        Some(result)
+    }
+    
+    fn bits(&self) -> usize {
+        match self.limbs.last() {
+            Some(top) => self.limbs.len() * 32 - top.leading_zeros() as usize,
+            None => 0,
+        }
+    }
+    
+    fn bit(&self, index: usize) -> bool {
+        self.limbs[index / 32] >> (index % 32) & 1 == 1
+    }
+    
+    fn shift_left_one(&mut self, low_bit: bool) {
+        let mut carry = low_bit as u32;
+        for limb in self.limbs.iter_mut() {
+            let next = *limb >> 31;
+            *limb = (*limb << 1) | carry;
+            carry = next;
+        }
+        if carry > 0 {
+            self.limbs.push(carry);
+        }
+    }
+}
+
+impl Ord for BigUint {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.limbs.len().cmp(&other.limbs.len())
+            .then_with(|| self.limbs.iter().rev().cmp(other.limbs.iter().rev()))
+    }
+}
+
+impl PartialOrd for BigUint {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
@@ -1,5 +1,16 @@
 use std::fmt;
 
+use crate::ast::span::Span;
+
+/// A sandboxing limit from `vm::Limits`, with the configured maximum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quota {
+    OutputBytes(usize),
+    ValueLength(usize),
+    Variables(usize),
+    Functions(usize),
+}
+
 #[derive(Debug, Clone)]
 pub enum VMError {
     ParseError(String),
@@ -7,8 +18,37 @@ pub enum VMError {
     ExecutionError(String),
     RuntimeError(String),
     StackUnderflow,
+    StackOverflow(String),
+    OutOfFuel(u64),
+    QuotaExceeded(Quota),
     UnknownFunction(String),
     InvalidOperation(String),
+    Located(Box<VMError>, Span),
+}
+
+impl VMError {
+    /// Attaches the span of the offending node. Errors that already carry
+    /// a span keep it, so the innermost node wins as an error propagates.
+    pub fn at(self, span: Span) -> Self {
+        match self {
+            VMError::Located(..) => self,
+            error => VMError::Located(Box::new(error), span),
+        }
+    }
+    
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            VMError::Located(_, span) => Some(*span),
+            _ => None,
+        }
+    }
+    
+    pub fn kind(&self) -> &VMError {
+        match self {
+            VMError::Located(error, _) => error,
+            error => error,
+        }
+    }
 }
 
 impl fmt::Display for VMError {
@@ -19,8 +59,24 @@ impl fmt::Display for VMError {
             VMError::ExecutionError(msg) => write!(f, "Execution error: {}", msg),
             VMError::RuntimeError(msg) => write!(f, "Runtime error: {}", msg),
             VMError::StackUnderflow => write!(f, "Stack underflow"),
+            VMError::StackOverflow(name) if name.is_empty() => write!(f, "Stack overflow: expressions nested too deeply"),
+            VMError::StackOverflow(name) => write!(f, "Stack overflow: too many nested calls in function {}", name),
+            VMError::OutOfFuel(steps) => write!(f, "Out of fuel after {} steps", steps),
+            VMError::QuotaExceeded(quota) => write!(f, "Quota exceeded: {}", quota),
             VMError::UnknownFunction(name) => write!(f, "Unknown function: {}", name),
             VMError::InvalidOperation(msg) => write!(f, "Invalid operation: {}", msg),
+            VMError::Located(error, span) => write!(f, "{} at {}", error, span),
+        }
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Quota::OutputBytes(max) => write!(f, "output is limited to {} bytes", max),
+            Quota::ValueLength(max) => write!(f, "values are limited to {} bytes", max),
+            Quota::Variables(max) => write!(f, "at most {} variables may exist at once", max),
+            Quota::Functions(max) => write!(f, "at most {} functions may be defined", max),
         }
     }
 }
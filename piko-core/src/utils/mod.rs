@@ -1,4 +1,5 @@
 pub mod base_26;
+mod bignum;
 pub mod error;
 
-pub use error::{VMError, VMResult};
+pub use error::{Quota, VMError, VMResult};
@@ -0,0 +1,115 @@
+use std::fmt;
+use std::io::{BufRead, Write};
+
+use crate::utils::base_26;
+use crate::utils::error::{VMError, VMResult};
+use super::{Value, VM};
+use super::constants::{FUNC_GREET, FUNC_INPUT, FUNC_OUTPUT};
+
+/// How many arguments a native function accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match *self {
+            Arity::Exact(n) => count == n,
+            Arity::AtLeast(n) => count >= n,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arity::Exact(n) => write!(f, "{}", n),
+            Arity::AtLeast(n) => write!(f, "at least {}", n),
+        }
+    }
+}
+
+type Builtin = fn(&[Value]) -> VMResult<String>;
+
+/// String functions available to every program through `c`. Positions and
+/// lengths are base-26 values, so "a" is the first character and "" means
+/// zero.
+const STRING_FUNCTIONS: &[(&str, Arity, Builtin)] = &[
+    ("cat", Arity::AtLeast(0), cat),
+    ("len", Arity::Exact(1), len),
+    ("at", Arity::Exact(2), at),
+    ("sub", Arity::Exact(3), sub),
+    ("rev", Arity::Exact(1), rev),
+    ("split", Arity::Exact(3), split),
+    ("join", Arity::AtLeast(1), join),
+];
+
+/// Registers the standard library on a new VM: the string functions plus
+/// greet, input and output as callable functions.
+pub fn register<W: Write, R: BufRead>(vm: &mut VM<W, R>) {
+    for &(name, arity, function) in STRING_FUNCTIONS {
+        vm.register_native(name, arity, move |_, args| function(args));
+    }
+    vm.register_native(FUNC_GREET, Arity::Exact(1), |vm, args| {
+        vm.write_line(&format!("hello {}", args[0]))?;
+        Ok(args[0].to_string())
+    });
+    vm.register_native(FUNC_INPUT, Arity::Exact(0), |vm, _| vm.read_line().map(|line| line.to_string()));
+    vm.register_native(FUNC_OUTPUT, Arity::Exact(1), |vm, args| {
+        vm.write_line(&args[0])?;
+        Ok(args[0].to_string())
+    });
+}
+
+/// Reads a base-26 position or length. Values too large for memory are
+/// clamped, since they are out of range for any string anyway.
+fn index(function: &str, value: &str) -> VMResult<usize> {
+    if let Some(c) = base_26::invalid_digit(value) {
+        return Err(VMError::InvalidOperation(format!(
+            "{} needs base-26 positions (a-z), but \"{}\" contains '{}'",
+            function, value, c.escape_default()
+        )));
+    }
+    Ok(base_26::to_index(value).unwrap_or(usize::MAX))
+}
+
+fn cat(args: &[Value]) -> VMResult<String> {
+    Ok(args.concat())
+}
+
+fn len(args: &[Value]) -> VMResult<String> {
+    Ok(base_26::from_index(args[0].chars().count()))
+}
+
+fn at(args: &[Value]) -> VMResult<String> {
+    let position = index("at", &args[1])?;
+    let ch = position.checked_sub(1).and_then(|i| args[0].chars().nth(i));
+    Ok(ch.map(String::from).unwrap_or_default())
+}
+
+fn sub(args: &[Value]) -> VMResult<String> {
+    let start = index("sub", &args[1])?.max(1);
+    let length = index("sub", &args[2])?;
+    Ok(args[0].chars().skip(start - 1).take(length).collect())
+}
+
+fn rev(args: &[Value]) -> VMResult<String> {
+    Ok(args[0].chars().rev().collect())
+}
+
+/// Returns one piece of a string split on a delimiter, since values cannot
+/// hold lists. Past the last piece the result is "".
+fn split(args: &[Value]) -> VMResult<String> {
+    if args[1].is_empty() {
+        return Err(VMError::InvalidOperation("split needs a non-empty delimiter".to_string()));
+    }
+    let position = index("split", &args[2])?;
+    let piece = position.checked_sub(1).and_then(|i| args[0].split(&*args[1]).nth(i));
+    Ok(piece.unwrap_or_default().to_string())
+}
+
+fn join(args: &[Value]) -> VMResult<String> {
+    Ok(args[1..].join(&*args[0]))
+}
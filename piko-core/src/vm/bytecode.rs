@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use crate::ast::expressions::BinaryOp;
+use crate::ast::lexer;
+use crate::ast::span::Span;
+use super::value::Value;
+
+/// One instruction of the stack machine. Operands index the constants or
+/// functions of the chunk the instruction belongs to, and jump targets are
+/// instruction indices within that chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// Pushes a constant.
+    Push(usize),
+    /// Pushes the value of a variable, or its name if it is unassigned.
+    Load(usize),
+    /// Assigns the top value to a variable, leaving it on the stack.
+    Store(usize),
+    /// Discards the given number of values.
+    Pop(usize),
+    /// Discards the value just below the top one.
+    Nip,
+    Dup,
+    Binary(BinaryOp),
+    Not,
+    Jump(usize),
+    /// Pops a value and jumps if it is "a".
+    JumpIfFalse(usize),
+    /// Calls a function by name with the given number of arguments from the
+    /// top of the stack, replacing them with the result.
+    Call(usize, usize),
+    /// Pops a value and returns it from the enclosing function call.
+    Return,
+    /// A `b` outside of any loop in its function, which fails when reached.
+    Break,
+    /// Reads a line of input into a variable and pushes it.
+    Input(usize),
+    /// Writes the top value, leaving it on the stack.
+    Output,
+    /// Defines a function of the chunk and pushes its name.
+    Define(usize),
+}
+
+/// Compiled code of a top-level form or a function body. Running it leaves
+/// the value of the code as the only value on the stack.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub code: Vec<Instruction>,
+    /// Source span of each instruction, for locating runtime errors.
+    pub spans: Vec<Span>,
+    /// Fuel each instruction costs: a step for every expression and loop
+    /// iteration that begins there, so both engines take the same steps.
+    pub costs: Vec<u64>,
+    /// Literals and names used by the code.
+    pub constants: Vec<Value>,
+    /// Functions defined by `Define` instructions in the code.
+    pub functions: Vec<FunctionProto>,
+    /// Span of the whole form or function body.
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionProto {
+    pub name: Value,
+    pub params: Arc<[Value]>,
+    pub chunk: Arc<Chunk>,
+}
+
+/// A compiled Piko program: one chunk per top-level form, run in order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+    pub forms: Vec<Chunk>,
+}
+
+impl Program {
+    /// Renders the program as a human-readable listing, with the functions
+    /// each chunk defines listed after it.
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+        for (index, chunk) in self.forms.iter().enumerate() {
+            chunk.disassemble_into(&format!("form {}", index), &mut out);
+        }
+        out
+    }
+}
+
+impl Chunk {
+    pub fn disassemble(&self, title: &str) -> String {
+        let mut out = String::new();
+        self.disassemble_into(title, &mut out);
+        out
+    }
+    
+    fn disassemble_into(&self, title: &str, out: &mut String) {
+        out.push_str(&format!("{} at {}:\n", title, self.span));
+        for (index, instruction) in self.code.iter().enumerate() {
+            out.push_str(&format!("{:>6}  {}\n", index, self.describe(instruction)));
+        }
+        for function in &self.functions {
+            let title = format!("function {} ({})", function.name, function.params.join(" "));
+            function.chunk.disassemble_into(&title, out);
+        }
+    }
+    
+    fn describe(&self, instruction: &Instruction) -> String {
+        let constant = |index: usize| self.constants.get(index).map_or("?", |value| &**value);
+        match *instruction {
+            Instruction::Push(index) => format!("push \"{}\"", lexer::escape(constant(index))),
+            Instruction::Load(index) => format!("load {}", constant(index)),
+            Instruction::Store(index) => format!("store {}", constant(index)),
+            Instruction::Pop(count) => format!("pop {}", count),
+            Instruction::Nip => "nip".to_string(),
+            Instruction::Dup => "dup".to_string(),
+            Instruction::Binary(op) => format!("op {}", op.symbol()),
+            Instruction::Not => "not".to_string(),
+            Instruction::Jump(target) => format!("jump {}", target),
+            Instruction::JumpIfFalse(target) => format!("jump-if-false {}", target),
+            Instruction::Call(name, count) => format!("call {} {}", constant(name), count),
+            Instruction::Return => "return".to_string(),
+            Instruction::Break => "break".to_string(),
+            Instruction::Input(index) => format!("input {}", constant(index)),
+            Instruction::Output => "output".to_string(),
+            Instruction::Define(index) => {
+                let name = self.functions.get(index).map_or("?", |function| &*function.name);
+                format!("define {}", name)
+            }
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::ast::PikoAst;
+use crate::ast::expressions::{ChainOp, Expression, Node};
+use crate::ast::span::Span;
+use super::bytecode::{Chunk, FunctionProto, Instruction, Program};
+use super::value::Value;
+
+/// A loop being compiled: where its result slot sits on the stack, and the
+/// jumps that leave it, to be pointed at its end once that is known.
+struct LoopContext {
+    height: usize,
+    exits: Vec<usize>,
+}
+
+/// Lowers parsed programs to bytecode with the same meaning as evaluating
+/// them directly. Every expression compiles to code that leaves exactly one
+/// value on the stack, so the stack height is known at each instruction and
+/// `b` can discard whatever an enclosing expression left behind.
+pub struct Compiler {
+    chunk: Chunk,
+    constants: HashMap<String, usize>,
+    height: usize,
+    loops: Vec<LoopContext>,
+    /// Steps taken since the last instruction, charged by the next one.
+    pending: u64,
+}
+
+impl Compiler {
+    pub fn compile(ast: &PikoAst) -> Program {
+        let mut program = Program::default();
+        Self::compile_forms(ast, &mut program);
+        program
+    }
+    
+    fn compile_forms(ast: &PikoAst, program: &mut Program) {
+        match ast {
+            PikoAst::Expression(node) => program.forms.push(Self::compile_node(node)),
+            PikoAst::Program(forms) => {
+                for form in forms {
+                    Self::compile_forms(form, program);
+                }
+            }
+        }
+    }
+    
+    /// Compiles one top-level form or function body into its own chunk.
+    pub fn compile_node(node: &Node) -> Chunk {
+        let mut compiler = Compiler {
+            chunk: Chunk {
+                code: Vec::new(),
+                spans: Vec::new(),
+                costs: Vec::new(),
+                constants: Vec::new(),
+                functions: Vec::new(),
+                span: node.span,
+            },
+            constants: HashMap::new(),
+            height: 0,
+            loops: Vec::new(),
+            pending: 0,
+        };
+        compiler.expression(node);
+        compiler.chunk
+    }
+    
+    fn emit(&mut self, instruction: Instruction, span: Span) -> usize {
+        self.height = match instruction {
+            Instruction::Push(_) | Instruction::Load(_) | Instruction::Dup
+            | Instruction::Input(_) | Instruction::Define(_) => self.height + 1,
+            Instruction::Pop(count) => self.height - count,
+            Instruction::Nip | Instruction::Binary(_) | Instruction::JumpIfFalse(_) => self.height - 1,
+            Instruction::Call(_, count) => self.height + 1 - count,
+            Instruction::Store(_) | Instruction::Not | Instruction::Jump(_) | Instruction::Return
+            | Instruction::Break | Instruction::Output => self.height,
+        };
+        self.chunk.code.push(instruction);
+        self.chunk.spans.push(span);
+        self.chunk.costs.push(std::mem::take(&mut self.pending));
+        self.chunk.code.len() - 1
+    }
+    
+    fn constant(&mut self, value: &str) -> usize {
+        if let Some(&index) = self.constants.get(value) {
+            return index;
+        }
+        self.chunk.constants.push(Value::from(value));
+        let index = self.chunk.constants.len() - 1;
+        self.constants.insert(value.to_string(), index);
+        index
+    }
+    
+    fn push(&mut self, value: &str, span: Span) {
+        let index = self.constant(value);
+        self.emit(Instruction::Push(index), span);
+    }
+    
+    /// Points the jump at `at` to the next instruction to be emitted.
+    fn patch(&mut self, at: usize) {
+        let target = self.chunk.code.len();
+        match &mut self.chunk.code[at] {
+            Instruction::Jump(to) | Instruction::JumpIfFalse(to) => *to = target,
+            _ => unreachable!("only jumps are patched"),
+        }
+    }
+    
+    /// Every expression takes a step when evaluation reaches it, which the
+    /// first instruction of its code charges.
+    fn expression(&mut self, node: &Node) {
+        self.pending += 1;
+        let span = node.span;
+        match &node.node {
+            Expression::Variable(name) => {
+                let index = self.constant(name);
+                self.emit(Instruction::Load(index), span);
+            }
+            Expression::Literal(value) => self.push(value, span),
+            Expression::BinaryOp(left, op, right) => {
+                self.expression(left);
+                self.expression(right);
+                self.emit(Instruction::Binary(*op), span);
+            }
+            Expression::Output(expr) => {
+                self.expression(expr);
+                self.emit(Instruction::Output, span);
+            }
+            Expression::Input(var) => self.input(var, span),
+            Expression::Assign(var, expr) => self.assign(var, expr, span),
+            Expression::Return(expr) => {
+                self.expression(expr);
+                self.emit(Instruction::Return, span);
+            }
+            Expression::Call(name, args) => self.call(name, args, span),
+            Expression::Function(name, params, body) => self.function(name, params, body, span),
+            Expression::Loop(condition, body) => self.loop_(condition.as_deref(), body, span),
+            Expression::If(condition, then_branch, else_branch) => {
+                self.expression(condition);
+                let to_else = self.emit(Instruction::JumpIfFalse(0), span);
+                let height = self.height;
+                self.expression(then_branch);
+                let to_end = self.emit(Instruction::Jump(0), span);
+                self.patch(to_else);
+                self.height = height;
+                match else_branch {
+                    Some(else_branch) => self.expression(else_branch),
+                    None => self.push("a", span),
+                }
+                self.patch(to_end);
+            }
+            Expression::And(left, right) => {
+                self.expression(left);
+                self.emit(Instruction::Dup, span);
+                let to_end = self.emit(Instruction::JumpIfFalse(0), span);
+                self.emit(Instruction::Pop(1), span);
+                self.expression(right);
+                self.patch(to_end);
+            }
+            Expression::Or(left, right) => {
+                self.expression(left);
+                self.emit(Instruction::Dup, span);
+                let to_right = self.emit(Instruction::JumpIfFalse(0), span);
+                let to_end = self.emit(Instruction::Jump(0), span);
+                self.patch(to_right);
+                self.emit(Instruction::Pop(1), span);
+                self.expression(right);
+                self.patch(to_end);
+            }
+            Expression::Not(expr) => {
+                self.expression(expr);
+                self.emit(Instruction::Not, span);
+            }
+            Expression::Break => self.break_(span),
+            Expression::ChainedOp(ops) => {
+                self.push("", span);
+                for op in ops {
+                    self.chain_op(op, span);
+                }
+            }
+            Expression::Block(exprs) => match exprs.split_first() {
+                Some((first, rest)) => {
+                    self.expression(first);
+                    for expr in rest {
+                        self.emit(Instruction::Pop(1), span);
+                        self.expression(expr);
+                    }
+                }
+                None => self.push("", span),
+            },
+        }
+    }
+    
+    /// Chain operations have no spans of their own, so their code is
+    /// located at the whole chain, like their errors are when evaluated.
+    fn chain_op(&mut self, op: &ChainOp, span: Span) {
+        if let ChainOp::Output = op {
+            self.emit(Instruction::Output, span);
+            return;
+        }
+        
+        self.emit(Instruction::Pop(1), span);
+        match op {
+            ChainOp::Input(var) => self.input(var, span),
+            ChainOp::Output => unreachable!("handled above"),
+            ChainOp::Assign(var, expr) => self.assign(var, expr, span),
+            ChainOp::Return(expr) => {
+                self.expression(expr);
+                self.emit(Instruction::Return, span);
+            }
+            ChainOp::Call(name, args) => self.call(name, args, span),
+            ChainOp::Function(name, params, body) => self.function(name, params, body, span),
+            ChainOp::Loop(condition, body) => self.loop_(condition.as_deref(), body, span),
+            ChainOp::Break => self.break_(span),
+        }
+    }
+    
+    fn input(&mut self, var: &str, span: Span) {
+        let index = self.constant(var);
+        self.emit(Instruction::Input(index), span);
+    }
+    
+    fn assign(&mut self, var: &str, expr: &Node, span: Span) {
+        self.expression(expr);
+        let index = self.constant(var);
+        self.emit(Instruction::Store(index), span);
+    }
+    
+    fn call(&mut self, name: &str, args: &[Node], span: Span) {
+        for arg in args {
+            self.expression(arg);
+        }
+        let index = self.constant(name);
+        self.emit(Instruction::Call(index, args.len()), span);
+    }
+    
+    fn function(&mut self, name: &str, params: &[String], body: &Node, span: Span) {
+        self.chunk.functions.push(FunctionProto {
+            name: Value::from(name),
+            params: params.iter().map(|param| Value::from(param.as_str())).collect(),
+            chunk: Arc::new(Self::compile_node(body)),
+        });
+        let index = self.chunk.functions.len() - 1;
+        self.emit(Instruction::Define(index), span);
+    }
+    
+    /// The loop keeps its result in a slot below its working values: "a" to
+    /// begin with, then the value of each completed iteration of the body.
+    fn loop_(&mut self, condition: Option<&Node>, body: &Node, span: Span) {
+        self.push("a", span);
+        self.loops.push(LoopContext { height: self.height, exits: Vec::new() });
+        
+        let start = self.chunk.code.len();
+        self.pending += 1;
+        if let Some(condition) = condition {
+            self.expression(condition);
+            let exit = self.emit(Instruction::JumpIfFalse(0), span);
+            self.loops.last_mut().unwrap().exits.push(exit);
+        }
+        self.expression(body);
+        self.emit(Instruction::Nip, span);
+        self.emit(Instruction::Jump(start), span);
+        
+        let context = self.loops.pop().unwrap();
+        for exit in context.exits {
+            self.patch(exit);
+        }
+        self.height = context.height;
+    }
+    
+    /// Leaves the innermost loop, dropping the values above its result slot.
+    /// The code after a `b` is unreachable, but it is compiled as if `b` had
+    /// produced a value so the stack heights stay consistent.
+    fn break_(&mut self, span: Span) {
+        let height = self.height;
+        match self.loops.last() {
+            Some(context) => {
+                let extra = self.height - context.height;
+                if extra > 0 {
+                    self.emit(Instruction::Pop(extra), span);
+                }
+                let exit = self.emit(Instruction::Jump(0), span);
+                self.loops.last_mut().unwrap().exits.push(exit);
+            }
+            None => {
+                self.emit(Instruction::Break, span);
+            }
+        }
+        self.height = height + 1;
+    }
+}
@@ -1,4 +1,4 @@
-pub const VM_VERSION: &str = "1.0";
+pub const VM_VERSION: &str = "1.1";
 
 pub const RESERVED_KEYWORDS: [&str; 8] = ["a", "f", "c", "l", "b", "r", "o", "i"];
 
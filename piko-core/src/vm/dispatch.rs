@@ -0,0 +1,126 @@
+use std::io::{BufRead, Write};
+
+use crate::utils::error::{VMError, VMResult};
+use super::bytecode::{Chunk, Instruction};
+use super::{Body, Flow, Value, VM};
+
+impl<W: Write, R: BufRead> VM<W, R> {
+    /// Runs a chunk on a fresh operand stack. Like evaluating an expression,
+    /// this produces the chunk's value or a `b`/`r` control transfer.
+    pub(super) fn run_chunk(&mut self, chunk: &Chunk) -> VMResult<Flow> {
+        let result = match self.nest() {
+            Ok(()) => self.run_code(chunk),
+            Err(error) => Err(error.at(chunk.span)),
+        };
+        self.nesting -= 1;
+        result
+    }
+    
+    fn run_code(&mut self, chunk: &Chunk) -> VMResult<Flow> {
+        let mut stack = Vec::new();
+        let mut pc = 0;
+        while let Some(&instruction) = chunk.code.get(pc) {
+            pc += 1;
+            // Calls recurse into the callee's chunk, so they are kept out of
+            // `run_instruction` and its larger frame stays off the host stack
+            // while a call is in progress.
+            let result = match self.charge(chunk.costs[pc - 1]) {
+                Ok(()) => match instruction {
+                    Instruction::Call(index, count) => self.run_call(&chunk.constants[index], count, &mut stack),
+                    _ => self.run_instruction(chunk, instruction, &mut stack, &mut pc),
+                },
+                Err(error) => Err(error),
+            };
+            match result {
+                Ok(None) => {}
+                Ok(Some(flow)) => return Ok(flow),
+                Err(error) => return Err(error.at(chunk.spans[pc - 1])),
+            }
+        }
+        let value = pop(&mut stack).map_err(|e| e.at(chunk.span))?;
+        Ok(Flow::Value(value))
+    }
+    
+    fn run_instruction(
+        &mut self,
+        chunk: &Chunk,
+        instruction: Instruction,
+        stack: &mut Vec<Value>,
+        pc: &mut usize,
+    ) -> VMResult<Option<Flow>> {
+        let value = match instruction {
+            Instruction::Push(index) => chunk.constants[index].clone(),
+            Instruction::Load(index) => {
+                let name = &chunk.constants[index];
+                self.variables.get(name).unwrap_or(name).clone()
+            }
+            Instruction::Store(index) => {
+                let value = top(stack)?.clone();
+                self.assign_variable(&chunk.constants[index], value)?;
+                return Ok(None);
+            }
+            Instruction::Pop(count) => {
+                let len = stack.len().checked_sub(count).ok_or(VMError::StackUnderflow)?;
+                stack.truncate(len);
+                return Ok(None);
+            }
+            Instruction::Nip => {
+                let value = pop(stack)?;
+                pop(stack)?;
+                value
+            }
+            Instruction::Dup => top(stack)?.clone(),
+            Instruction::Binary(op) => {
+                let right = pop(stack)?;
+                let left = pop(stack)?;
+                self.apply_binary_op(&left, &op, &right)?
+            }
+            Instruction::Not => {
+                let value = pop(stack)?;
+                self.bool_value(self.is_false(&value))
+            }
+            Instruction::Jump(target) => {
+                *pc = target;
+                return Ok(None);
+            }
+            Instruction::JumpIfFalse(target) => {
+                if self.is_false(&pop(stack)?) {
+                    *pc = target;
+                }
+                return Ok(None);
+            }
+            Instruction::Call(..) => unreachable!("calls are dispatched by run_code"),
+            Instruction::Return => return Ok(Some(Flow::Return(pop(stack)?, chunk.spans[*pc - 1]))),
+            Instruction::Break => return Ok(Some(Flow::Break(chunk.spans[*pc - 1]))),
+            Instruction::Input(index) => self.read_input(&chunk.constants[index])?,
+            Instruction::Output => {
+                self.write_line(top(stack)?)?;
+                return Ok(None);
+            }
+            Instruction::Define(index) => {
+                let proto = &chunk.functions[index];
+                self.define_function(&proto.name, &proto.params, Body::Compiled(proto.chunk.clone()))?
+            }
+        };
+        self.check_value(&value)?;
+        stack.push(value);
+        Ok(None)
+    }
+    
+    fn run_call(&mut self, name: &str, count: usize, stack: &mut Vec<Value>) -> VMResult<Option<Flow>> {
+        let start = stack.len().checked_sub(count).ok_or(VMError::StackUnderflow)?;
+        let args = stack.split_off(start);
+        let value = self.call_function(name, args)?;
+        self.check_value(&value)?;
+        stack.push(value);
+        Ok(None)
+    }
+}
+
+fn pop(stack: &mut Vec<Value>) -> VMResult<Value> {
+    stack.pop().ok_or(VMError::StackUnderflow)
+}
+
+fn top(stack: &[Value]) -> VMResult<&Value> {
+    stack.last().ok_or(VMError::StackUnderflow)
+}
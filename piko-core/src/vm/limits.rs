@@ -0,0 +1,34 @@
+/// Resource limits the VM enforces while running a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// How many function calls may be in progress at once. Each call uses
+    /// host stack, so raise this only when running on a larger stack.
+    pub max_call_depth: usize,
+    /// How deeply evaluation may recurse on the host stack: one level per
+    /// expression being evaluated by the tree walker, and one per chunk
+    /// being run by the bytecode engine. Function bodies count towards it
+    /// too, so it bounds deep nesting inside recursive calls as well.
+    pub max_nesting: usize,
+    /// Total bytes the program may write, newlines included.
+    pub max_output_bytes: Option<usize>,
+    /// Longest value, in bytes, any expression may produce.
+    pub max_value_len: Option<usize>,
+    /// How many variables may exist at once, counting the globals and the
+    /// locals and parameters of every call in progress.
+    pub max_variables: Option<usize>,
+    /// How many functions the program may define. Natives do not count.
+    pub max_functions: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_call_depth: 200,
+            max_nesting: 400,
+            max_output_bytes: None,
+            max_value_len: None,
+            max_variables: None,
+            max_functions: None,
+        }
+    }
+}
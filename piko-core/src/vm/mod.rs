@@ -1,210 +1,664 @@
 use std::collections::HashMap;
 use std::io::{BufRead, Write};
+use std::sync::Arc;
 use crate::ast::PikoAst;
-use crate::ast::expressions::{Expression, BinaryOp};
-use crate::utils::error::{VMError, VMResult};
+use crate::ast::expressions::BinaryOp;
+use crate::ast::span::Span;
+use crate::utils::error::{Quota, VMError, VMResult};
 use crate::utils::base_26;
+use bytecode::{Chunk, Program};
+use compiler::Compiler;
+use scope::Scopes;
+use tree::{ChainOp, Expr, Function, Lowering, Node};
 
+mod builtins;
+pub mod bytecode;
+pub mod compiler;
 pub mod constants;
+mod dispatch;
+mod limits;
+mod module;
+mod scope;
+mod tree;
+mod value;
+
+pub use builtins::Arity;
+pub use limits::Limits;
+pub use value::Value;
+
+/// How the VM runs programs. Both engines share all VM state, so functions
+/// defined under one can be called under the other.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Engine {
+    /// Evaluates the syntax tree directly.
+    #[default]
+    TreeWalking,
+    /// Compiles each form to bytecode and runs it on a stack machine.
+    Bytecode,
+}
+
+/// Outcome of evaluating an expression: either a value for the enclosing
+/// expression, or a control transfer unwinding to the nearest loop (`b`) or
+/// function call (`r`). Control flow never travels through Piko values, so
+/// no string can be mistaken for it. Transfers carry the span of the `b` or
+/// `r` that started them, for reporting one that escapes.
+enum Flow {
+    Value(Value),
+    Break(Span),
+    Return(Value, Span),
+}
+
+/// Unwraps a `Flow::Value`, or propagates any other outcome to the caller.
+macro_rules! value {
+    ($flow:expr) => {
+        match $flow {
+            Flow::Value(value) => value,
+            flow => return Ok(flow),
+        }
+    };
+}
+
+/// A function defined by the program, with its body in the form of the
+/// engine that defined it.
+#[derive(Clone)]
+struct UserFunction {
+    name: Value,
+    params: Arc<[Value]>,
+    body: Body,
+}
+
+#[derive(Clone)]
+enum Body {
+    Tree(Arc<Node>),
+    Compiled(Arc<Chunk>),
+}
+
+/// A host function callable from Piko with `c`. It receives the VM, so it
+/// can read input, write output or touch variables, and the call arguments.
+/// Natives are `Send + Sync` so a VM can move between threads whenever its
+/// output and input can.
+pub type NativeFn<W, R> = Arc<dyn Fn(&mut VM<W, R>, &[Value]) -> VMResult<String> + Send + Sync>;
 
 pub struct VM<W: Write, R: BufRead> {
-    functions: HashMap<String, (Vec<String>, Expression)>,
-    variables: HashMap<String, String>,
+    functions: HashMap<String, UserFunction>,
+    natives: HashMap<String, (Arity, NativeFn<W, R>)>,
+    variables: Scopes,
     output: W,
     input: R,
+    limits: Limits,
+    fuel: Option<u64>,
+    steps: u64,
+    output_bytes: usize,
+    engine: Engine,
+    /// "a" and "b", the results of comparisons.
+    booleans: [Value; 2],
+    /// "", the value of an empty block and the start of a chain.
+    empty: Value,
+    /// Levels of host recursion in progress, bounded by `max_nesting`.
+    nesting: usize,
+    /// Names of the program functions being called, innermost last.
+    calls: Vec<Value>,
 }
 
 impl<W: Write, R: BufRead> VM<W, R> {
     pub fn new(output: W, input: R) -> Self {
-        VM {
+        Self::with_limits(output, input, Limits::default())
+    }
+    
+    pub fn with_limits(output: W, input: R, limits: Limits) -> Self {
+        let mut vm = VM {
             functions: HashMap::new(),
-            variables: HashMap::new(),
+            natives: HashMap::new(),
+            variables: Scopes::default(),
             output,
             input,
-        }
+            limits,
+            fuel: None,
+            steps: 0,
+            output_bytes: 0,
+            engine: Engine::default(),
+            booleans: [Value::from("a"), Value::from("b")],
+            empty: Value::from(""),
+            nesting: 0,
+            calls: Vec::new(),
+        };
+        builtins::register(&mut vm);
+        vm
+    }
+    
+    /// Makes a host function callable from Piko as `(c name ...)`, replacing
+    /// any native of the same name. Functions defined by the program itself
+    /// take precedence over natives.
+    pub fn register_native<F>(&mut self, name: &str, arity: Arity, function: F)
+    where
+        F: Fn(&mut VM<W, R>, &[Value]) -> VMResult<String> + Send + Sync + 'static,
+    {
+        self.natives.insert(name.to_string(), (arity, Arc::new(function)));
     }
     
     pub fn get_output(&mut self) -> &mut W {
         &mut self.output
     }
     
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+    
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+    
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+    
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+    
+    /// Sets how many more steps the VM may take, or `None` for no limit.
+    /// Every expression evaluated and every loop iteration costs one step,
+    /// and arithmetic on long values costs more in proportion to its work.
+    /// Both engines take the same steps for the same program. Running out
+    /// stops execution with `VMError::OutOfFuel`. Definitions and variables
+    /// made before that point are kept, so a host can top up the fuel and
+    /// carry on with the next program.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+    
+    /// Adds to the remaining fuel. Has no effect when fuel is unlimited.
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(remaining) = &mut self.fuel {
+            *remaining = remaining.saturating_add(fuel);
+        }
+    }
+    
+    pub fn remaining_fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+    
+    /// Steps taken since the VM was created.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+    
+    /// Starts counting output towards `max_output_bytes` afresh, for hosts
+    /// that take the output away between programs.
+    pub fn reset_output_bytes(&mut self) {
+        self.output_bytes = 0;
+    }
+    
+    pub fn get_variable(&self, name: &str) -> Option<&str> {
+        self.variables.get(name).map(|value| &**value)
+    }
+    
+    pub fn set_variable(&mut self, name: &str, value: &str) {
+        self.variables.assign(name, Value::from(value));
+    }
+    
+    pub fn remove_variable(&mut self, name: &str) -> Option<String> {
+        self.variables.remove(name).map(|value| value.to_string())
+    }
+    
+    /// All visible variables with their values, sorted by name. From a
+    /// native called inside a function these are its locals and the globals.
+    pub fn variables(&self) -> Vec<(&str, &str)> {
+        let mut variables = self.variables.visible().into_iter().collect::<Vec<_>>();
+        variables.sort();
+        variables
+    }
+    
+    /// Every callable function with its arity, sorted by name. Functions the
+    /// program defined hide natives of the same name, as they do for `c`.
+    pub fn functions(&self) -> Vec<(&str, Arity)> {
+        let mut functions = self.natives.iter()
+            .filter(|(name, _)| !self.functions.contains_key(*name))
+            .map(|(name, (arity, _))| (name.as_str(), *arity))
+            .chain(self.functions.iter().map(|(name, function)| (name.as_str(), Arity::Exact(function.params.len()))))
+            .collect::<Vec<_>>();
+        functions.sort_by_key(|(name, _)| *name);
+        functions
+    }
+    
+    pub fn has_function(&self, name: &str) -> bool {
+        self.functions.contains_key(name) || self.natives.contains_key(name)
+    }
+    
+    /// Calls a function as `(c name args...)` would and returns its value.
+    pub fn call(&mut self, name: &str, args: &[&str]) -> VMResult<String> {
+        let args = args.iter().map(|&arg| Value::from(arg)).collect();
+        self.call_function(name, args).map(|value| value.to_string())
+    }
+    
     pub fn execute(&mut self, ast: PikoAst) -> VMResult<()> {
+        if self.engine == Engine::Bytecode {
+            return self.execute_compiled(&Compiler::compile(&ast));
+        }
+        self.execute_tree(&ast, &mut Lowering::default())
+    }
+    
+    fn execute_tree(&mut self, ast: &PikoAst, lowering: &mut Lowering) -> VMResult<()> {
         match ast {
             PikoAst::Expression(expr) => {
-                self.evaluate_expression(&expr)?;
+                let expr = lowering.node(expr);
+                let flow = self.evaluate_expression(&expr)?;
+                Self::finish_form(flow)?;
             }
             PikoAst::Program(nodes) => {
                 for node in nodes {
-                    self.execute(node)?;
+                    self.execute_tree(node, lowering)?;
                 }
             }
         }
         Ok(())
     }
     
-    fn evaluate_expression(&mut self, expr: &Expression) -> VMResult<String> {
-        match expr {
-            Expression::Variable(name) => {
-                Ok(self.variables.get(name).cloned().unwrap_or_else(|| name.clone()))
-            }
-            Expression::Literal(value) => Ok(value.clone()),
-            Expression::BinaryOp(left, op, right) => {
-                let left_val = self.evaluate_expression(left)?;
-                let right_val = self.evaluate_expression(right)?;
-                self.apply_binary_op(&left_val, op, &right_val)
-            }
-            Expression::Output(expr) => {
-                let value = self.evaluate_expression(expr)?;
-                writeln!(self.output, "{}", value)
-                    .map_err(|e| VMError::ExecutionError(e.to_string()))?;
-                Ok(value)
-            }
-            Expression::Input(var) => {
-                let mut input = String::new();
-                self.input.read_line(&mut input)
-                    .map_err(|e| VMError::ExecutionError(e.to_string()))?;
-                let input = input.trim().to_string();
-                self.variables.insert(var.clone(), input.clone());
-                Ok(input)
-            }
-            Expression::Assign(var, expr) => {
-                let value = self.evaluate_expression(expr)?;
-                self.variables.insert(var.clone(), value.clone());
-                Ok(value)
-            }
-            Expression::Return(expr) => self.evaluate_expression(expr),
-            Expression::Call(func, args) => {
-                let arg_values = args.iter()
-                    .map(|arg| self.evaluate_expression(arg))
-                    .collect::<VMResult<Vec<_>>>()?;
-                self.call_function(func, arg_values)
-            }
-            Expression::Function(name, params, body) => {
-                self.functions.insert(name.clone(), (params.clone(), body.as_ref().clone()));
-                Ok(format!("function_{}", name))
-            }
-            Expression::Loop(condition, body) => {
-                self.execute_loop(condition.as_deref(), body)
-            }
-            Expression::Break => Ok("break".to_string()),
-            Expression::ChainedOp(ops) => {
-                let mut result = String::new();
-                for op in ops {
-                    result = self.execute_chain_op(op, result)?;
-                }
-                Ok(result)
+    /// Runs a compiled program, whichever engine is selected.
+    pub fn execute_compiled(&mut self, program: &Program) -> VMResult<()> {
+        for chunk in &program.forms {
+            let flow = self.run_chunk(chunk)?;
+            Self::finish_form(flow)?;
+        }
+        Ok(())
+    }
+    
+    fn finish_form(flow: Flow) -> VMResult<()> {
+        match flow {
+            Flow::Value(_) => Ok(()),
+            Flow::Break(span) => Err(VMError::RuntimeError("b used outside of a loop".to_string()).at(span)),
+            Flow::Return(_, span) => Err(VMError::RuntimeError("r used outside of a function".to_string()).at(span)),
+        }
+    }
+    
+    fn evaluate_expression(&mut self, expr: &Node) -> VMResult<Flow> {
+        let result = match self.nest().and_then(|()| self.step()) {
+            Ok(()) => self.evaluate_node(&expr.node, expr.span),
+            Err(error) => Err(error),
+        };
+        self.nesting -= 1;
+        result.map_err(|e| e.at(expr.span))
+    }
+    
+    /// Enters one level of host recursion. Each level costs host stack, so
+    /// running out of levels is reported as a stack overflow in the
+    /// innermost function. The caller leaves the level again even when
+    /// this fails.
+    fn nest(&mut self) -> VMResult<()> {
+        self.nesting += 1;
+        if self.nesting > self.limits.max_nesting {
+            let name = self.calls.last().map_or("", |name| name);
+            return Err(VMError::StackOverflow(name.to_string()));
+        }
+        Ok(())
+    }
+    
+    fn step(&mut self) -> VMResult<()> {
+        self.charge(1)
+    }
+    
+    /// Takes `cost` steps at once. When less fuel is left, what remains is
+    /// used up and execution stops.
+    fn charge(&mut self, cost: u64) -> VMResult<()> {
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel < cost {
+                return Err(self.exhaust_fuel());
             }
-            Expression::Block(exprs) => {
-                let mut result = String::new();
-                for expr in exprs {
-                    result = self.evaluate_expression(expr)?;
-                }
-                Ok(result)
+            *fuel -= cost;
+        }
+        self.steps += cost;
+        Ok(())
+    }
+    
+    /// Uses up the remaining fuel, for work that would cost more than is
+    /// left.
+    fn exhaust_fuel(&mut self) -> VMError {
+        if let Some(fuel) = &mut self.fuel {
+            self.steps += *fuel;
+            *fuel = 0;
+        }
+        VMError::OutOfFuel(self.steps)
+    }
+    
+    /// Evaluation recurses through here once per nested expression, so the
+    /// work of each kind of expression lives in a function of its own and
+    /// only the frame of the kind being evaluated takes up host stack.
+    fn evaluate_node(&mut self, expr: &Expr, span: Span) -> VMResult<Flow> {
+        let result = match expr {
+            Expr::Variable(name) => Ok(Flow::Value(self.variables.get(name).unwrap_or(name).clone())),
+            Expr::Literal(value) => Ok(Flow::Value(value.clone())),
+            Expr::BinaryOp(left, op, right) => self.evaluate_binary_op(left, op, right),
+            Expr::Output(expr) => self.evaluate_output(expr),
+            Expr::Input(var) => self.read_input(var).map(Flow::Value),
+            Expr::Assign(var, expr) => self.evaluate_assign(var, expr),
+            Expr::Return(expr) => self.evaluate_return(expr, span),
+            Expr::Call(func, args) => self.evaluate_call(func, args),
+            Expr::Function(function) => self.define_tree_function(function).map(Flow::Value),
+            Expr::Loop(condition, body) => self.execute_loop(condition.as_deref(), body),
+            Expr::If(condition, then_branch, else_branch) => {
+                self.evaluate_if(condition, then_branch, else_branch.as_deref())
             }
+            Expr::And(left, right) => self.evaluate_logical(left, right, true),
+            Expr::Or(left, right) => self.evaluate_logical(left, right, false),
+            Expr::Not(expr) => self.evaluate_not(expr),
+            Expr::Break => Ok(Flow::Break(span)),
+            Expr::ChainedOp(ops) => self.evaluate_chain(ops, span),
+            Expr::Block(exprs) => self.evaluate_block(exprs),
+        };
+        if let Ok(Flow::Value(value)) = &result {
+            self.check_value(value)?;
+        }
+        result
+    }
+    
+    fn evaluate_binary_op(&mut self, left: &Node, op: &BinaryOp, right: &Node) -> VMResult<Flow> {
+        let left = value!(self.evaluate_expression(left)?);
+        let right = value!(self.evaluate_expression(right)?);
+        Ok(Flow::Value(self.apply_binary_op(&left, op, &right)?))
+    }
+    
+    fn evaluate_output(&mut self, expr: &Node) -> VMResult<Flow> {
+        let value = value!(self.evaluate_expression(expr)?);
+        self.write_line(&value)?;
+        Ok(Flow::Value(value))
+    }
+    
+    fn evaluate_assign(&mut self, var: &str, expr: &Node) -> VMResult<Flow> {
+        let value = value!(self.evaluate_expression(expr)?);
+        self.assign_variable(var, value.clone())?;
+        Ok(Flow::Value(value))
+    }
+    
+    fn evaluate_return(&mut self, expr: &Node, span: Span) -> VMResult<Flow> {
+        let value = value!(self.evaluate_expression(expr)?);
+        Ok(Flow::Return(value, span))
+    }
+    
+    fn evaluate_call(&mut self, func: &str, args: &[Node]) -> VMResult<Flow> {
+        let mut arg_values = Vec::with_capacity(args.len());
+        for arg in args {
+            arg_values.push(value!(self.evaluate_expression(arg)?));
+        }
+        Ok(Flow::Value(self.call_function(func, arg_values)?))
+    }
+    
+    fn evaluate_if(&mut self, condition: &Node, then_branch: &Node, else_branch: Option<&Node>) -> VMResult<Flow> {
+        let condition = value!(self.evaluate_expression(condition)?);
+        if !self.is_false(&condition) {
+            self.evaluate_expression(then_branch)
+        } else if let Some(else_branch) = else_branch {
+            self.evaluate_expression(else_branch)
+        } else {
+            Ok(Flow::Value(self.bool_value(false)))
+        }
+    }
+    
+    /// `&&` and `||`: the right operand is evaluated only when the left one
+    /// does not already decide the result.
+    fn evaluate_logical(&mut self, left: &Node, right: &Node, and: bool) -> VMResult<Flow> {
+        let left = value!(self.evaluate_expression(left)?);
+        if self.is_false(&left) == and {
+            Ok(Flow::Value(left))
+        } else {
+            self.evaluate_expression(right)
+        }
+    }
+    
+    fn evaluate_not(&mut self, expr: &Node) -> VMResult<Flow> {
+        let value = value!(self.evaluate_expression(expr)?);
+        Ok(Flow::Value(self.bool_value(self.is_false(&value))))
+    }
+    
+    /// Chain operations have no spans of their own, so they share the
+    /// chain's `span`.
+    fn evaluate_chain(&mut self, ops: &[ChainOp], span: Span) -> VMResult<Flow> {
+        let mut result = self.empty.clone();
+        for op in ops {
+            result = value!(self.execute_chain_op(op, result, span)?);
+        }
+        Ok(Flow::Value(result))
+    }
+    
+    fn evaluate_block(&mut self, exprs: &[Node]) -> VMResult<Flow> {
+        let mut result = self.empty.clone();
+        for expr in exprs {
+            result = value!(self.evaluate_expression(expr)?);
+        }
+        Ok(Flow::Value(result))
+    }
+    
+    fn read_input(&mut self, var: &str) -> VMResult<Value> {
+        let input = self.read_line()?;
+        self.assign_variable(var, input.clone())?;
+        Ok(input)
+    }
+    
+    fn read_line(&mut self) -> VMResult<Value> {
+        let mut input = String::new();
+        self.input.read_line(&mut input)
+            .map_err(|e| VMError::ExecutionError(e.to_string()))?;
+        let input = Value::from(input.trim());
+        self.check_value(&input)?;
+        Ok(input)
+    }
+    
+    fn write_line(&mut self, value: &str) -> VMResult<()> {
+        let bytes = self.output_bytes + value.len() + 1;
+        if let Some(max) = self.limits.max_output_bytes.filter(|&max| bytes > max) {
+            return Err(VMError::QuotaExceeded(Quota::OutputBytes(max)));
+        }
+        writeln!(self.output, "{}", value)
+            .map_err(|e| VMError::ExecutionError(e.to_string()))?;
+        self.output_bytes = bytes;
+        Ok(())
+    }
+    
+    fn check_value(&self, value: &str) -> VMResult<()> {
+        match self.limits.max_value_len {
+            Some(max) if value.len() > max => Err(VMError::QuotaExceeded(Quota::ValueLength(max))),
+            _ => Ok(()),
+        }
+    }
+    
+    fn assign_variable(&mut self, name: &str, value: Value) -> VMResult<()> {
+        let full = |max: &usize| self.variables.len() >= *max && self.variables.creates(name);
+        if let Some(max) = self.limits.max_variables.filter(full) {
+            return Err(VMError::QuotaExceeded(Quota::Variables(max)));
+        }
+        self.variables.assign(name, value);
+        Ok(())
+    }
+    
+    fn define_tree_function(&mut self, function: &Function) -> VMResult<Value> {
+        self.define_function(&function.name, &function.params, Body::Tree(function.body.clone()))
+    }
+    
+    fn define_function(&mut self, name: &Value, params: &Arc<[Value]>, body: Body) -> VMResult<Value> {
+        let full = |max: &usize| self.functions.len() >= *max && !self.functions.contains_key(&**name);
+        if let Some(max) = self.limits.max_functions.filter(full) {
+            return Err(VMError::QuotaExceeded(Quota::Functions(max)));
         }
+        let (name, params) = (name.clone(), params.clone());
+        self.functions.insert(name.to_string(), UserFunction { name: name.clone(), params, body });
+        Ok(name)
     }
     
-    fn apply_binary_op(&self, left: &str, op: &BinaryOp, right: &str) -> VMResult<String> {
+    fn apply_binary_op(&mut self, left_value: &Value, op: &BinaryOp, right_value: &Value) -> VMResult<Value> {
+        let (left, right) = (&**left_value, &**right_value);
+        for operand in [left, right] {
+            if let Some(c) = base_26::invalid_digit(operand) {
+                return Err(VMError::InvalidOperation(format!(
+                    "{} needs base-26 operands (a-z), but \"{}\" contains '{}'",
+                    op.symbol(), operand, c.escape_default()
+                )));
+            }
+        }
+        self.charge(Self::arithmetic_cost(op, left.len(), right.len()))?;
+        
         let result = match op {
+            BinaryOp::Min if base_26::compare_le(left, right) => return Ok(left_value.clone()),
+            BinaryOp::Max if base_26::compare_ge(left, right) => return Ok(left_value.clone()),
+            BinaryOp::Min | BinaryOp::Max => return Ok(right_value.clone()),
             BinaryOp::Add => base_26::add(left, right),
             BinaryOp::Sub => base_26::sub(left, right),
             BinaryOp::Mul => base_26::mul(left, right),
             BinaryOp::Div => base_26::div(left, right),
-            BinaryOp::Lt => self.bool_to_string(base_26::compare_lt(left, right)),
-            BinaryOp::Gt => self.bool_to_string(base_26::compare_gt(left, right)),
-            BinaryOp::Le => self.bool_to_string(base_26::compare_le(left, right)),
-            BinaryOp::Ge => self.bool_to_string(base_26::compare_ge(left, right)),
-            BinaryOp::Eq => self.bool_to_string(base_26::compare_eq(left, right)),
-            BinaryOp::Ne => self.bool_to_string(base_26::compare_ne(left, right)),
+            BinaryOp::Rem => base_26::rem(left, right),
+            BinaryOp::Pow => {
+                let affordable = self.fuel.map(Self::affordable_len);
+                let result = match self.limits.max_value_len.into_iter().chain(affordable).min() {
+                    Some(max_len) => match base_26::pow_within(left, right, max_len) {
+                        Some(result) => result,
+                        None if affordable.is_some_and(|len| len == max_len) => return Err(self.exhaust_fuel()),
+                        None => return Err(VMError::QuotaExceeded(Quota::ValueLength(max_len))),
+                    },
+                    None => base_26::pow(left, right),
+                };
+                self.charge(Self::result_cost(result.len()))?;
+                result
+            }
+            BinaryOp::Lt => return Ok(self.bool_value(base_26::compare_lt(left, right))),
+            BinaryOp::Gt => return Ok(self.bool_value(base_26::compare_gt(left, right))),
+            BinaryOp::Le => return Ok(self.bool_value(base_26::compare_le(left, right))),
+            BinaryOp::Ge => return Ok(self.bool_value(base_26::compare_ge(left, right))),
+            BinaryOp::Eq => return Ok(self.bool_value(base_26::compare_eq(left, right))),
+            BinaryOp::Ne => return Ok(self.bool_value(base_26::compare_ne(left, right))),
+        };
+        Ok(Value::from(result))
+    }
+    
+    /// Extra steps for an operation on values too long for machine words.
+    /// Converting between letters and numbers takes time quadratic in the
+    /// length, and writing out the result dominates, so operands are
+    /// charged len²/512 steps and the longest result the operation can
+    /// produce len²/64. Short values cost nothing extra. The length of a
+    /// power is only known once computed, so `^` gives up on results longer
+    /// than the remaining fuel could pay for and pays for it afterwards.
+    fn arithmetic_cost(op: &BinaryOp, left: usize, right: usize) -> u64 {
+        let result = match op {
+            BinaryOp::Add => left.max(right) + 1,
+            BinaryOp::Sub | BinaryOp::Div => left,
+            BinaryOp::Mul => left + right,
+            BinaryOp::Rem => right,
+            _ => 0,
         };
-        Ok(result)
+        let operand = |len: usize| (len as u64).pow(2) / 512;
+        operand(left) + operand(right) + Self::result_cost(result)
+    }
+    
+    fn result_cost(len: usize) -> u64 {
+        (len as u64).pow(2) / 64
+    }
+    
+    /// The longest result whose `result_cost` fits in `fuel`.
+    fn affordable_len(fuel: u64) -> usize {
+        usize::try_from(fuel.saturating_mul(64).saturating_add(63).isqrt()).unwrap_or(usize::MAX)
     }
     
-    fn bool_to_string(&self, value: bool) -> String {
-        if value { "b" } else { "a" }.to_string()
+    fn bool_value(&self, value: bool) -> Value {
+        self.booleans[value as usize].clone()
     }
     
-    fn execute_loop(&mut self, condition: Option<&Expression>, body: &Expression) -> VMResult<String> {
+    /// Runs a loop until its condition is "a" or its body breaks. The loop
+    /// evaluates to the last value its body produced, or "a" if the body
+    /// never completed.
+    fn execute_loop(&mut self, condition: Option<&Node>, body: &Node) -> VMResult<Flow> {
+        let mut result = self.bool_value(false);
         loop {
+            self.step()?;
             if let Some(cond) = condition {
-                let cond_result = self.evaluate_expression(cond)?;
-                if self.is_false(&cond_result) {
-                    break;
+                match self.evaluate_expression(cond)? {
+                    Flow::Value(cond_result) if self.is_false(&cond_result) => break,
+                    Flow::Value(_) => {}
+                    Flow::Break(_) => break,
+                    flow @ Flow::Return(..) => return Ok(flow),
                 }
             }
             
-            let result = self.evaluate_expression(body)?;
-            if result == "break" {
-                break;
+            match self.evaluate_expression(body)? {
+                Flow::Value(value) => result = value,
+                Flow::Break(_) => break,
+                flow @ Flow::Return(..) => return Ok(flow),
             }
         }
-        Ok("loop_completed".to_string())
+        Ok(Flow::Value(result))
     }
     
     fn is_false(&self, value: &str) -> bool {
         value == "a"
     }
     
-    fn execute_chain_op(&mut self, op: &crate::ast::expressions::ChainOp, current_result: String) -> VMResult<String> {
+    fn execute_chain_op(&mut self, op: &ChainOp, current_result: Value, span: Span) -> VMResult<Flow> {
         match op {
-            crate::ast::expressions::ChainOp::Input(var) => {
-                let mut input = String::new();
-                self.input.read_line(&mut input)
-                    .map_err(|e| VMError::ExecutionError(e.to_string()))?;
-                let input = input.trim().to_string();
-                self.variables.insert(var.clone(), input.clone());
-                Ok(input)
-            }
-            crate::ast::expressions::ChainOp::Output => {
-                writeln!(self.output, "{}", current_result)
-                    .map_err(|e| VMError::ExecutionError(e.to_string()))?;
-                Ok(current_result)
-            }
-            crate::ast::expressions::ChainOp::Assign(var, expr) => {
-                let value = self.evaluate_expression(expr)?;
-                self.variables.insert(var.clone(), value.clone());
-                Ok(value)
-            }
-            crate::ast::expressions::ChainOp::Return(expr) => {
-                self.evaluate_expression(expr)
-            }
-            crate::ast::expressions::ChainOp::Call(func, args) => {
-                let arg_values = args.iter()
-                    .map(|arg| self.evaluate_expression(arg))
-                    .collect::<VMResult<Vec<_>>>()?;
-                self.call_function(func, arg_values)
-            }
-            crate::ast::expressions::ChainOp::Function(name, params, body) => {
-                self.functions.insert(name.clone(), (params.clone(), body.as_ref().clone()));
-                Ok(format!("function_{}", name))
+            ChainOp::Input(var) => Ok(Flow::Value(self.read_input(var)?)),
+            ChainOp::Output => {
+                self.write_line(&current_result)?;
+                Ok(Flow::Value(current_result))
             }
-            crate::ast::expressions::ChainOp::Loop(condition, body) => {
-                self.execute_loop(condition.as_deref(), body)
-            }
-            crate::ast::expressions::ChainOp::Break => Ok("break".to_string()),
+            ChainOp::Assign(var, expr) => self.evaluate_assign(var, expr),
+            ChainOp::Return(expr) => self.evaluate_return(expr, span),
+            ChainOp::Call(func, args) => self.evaluate_call(func, args),
+            ChainOp::Function(function) => Ok(Flow::Value(self.define_tree_function(function)?)),
+            ChainOp::Loop(condition, body) => self.execute_loop(condition.as_deref(), body),
+            ChainOp::Break => Ok(Flow::Break(span)),
+        }
+    }
+    
+    fn call_function(&mut self, name: &str, args: Vec<Value>) -> VMResult<Value> {
+        if !self.functions.contains_key(name) && self.natives.contains_key(name) {
+            return self.call_native(name, args);
+        }
+        let UserFunction { name: callee, params, body } = self.enter_function(name, args.len())?;
+        self.variables.push_frame(params.iter().cloned().zip(args).collect());
+        self.calls.push(callee);
+        let result = match &body {
+            Body::Tree(node) => self.evaluate_expression(node),
+            Body::Compiled(chunk) => self.run_chunk(chunk),
+        };
+        self.calls.pop();
+        self.variables.pop_frame()?;
+        Self::function_result(name, result)
+    }
+    
+    fn call_native(&mut self, name: &str, args: Vec<Value>) -> VMResult<Value> {
+        let (arity, native) = self.natives[name].clone();
+        if !arity.accepts(args.len()) {
+            return Err(VMError::RuntimeError(format!(
+                "Function {} expects {} arguments, got {}",
+                name, arity, args.len()
+            )));
         }
+        native(self, &args).map(Value::from)
     }
     
-    fn call_function(&mut self, name: &str, args: Vec<String>) -> VMResult<String> {
-        let (params, body) = self.functions.get(name).cloned()
+    /// Looks up a function the program defined and checks that a call with
+    /// `arg_count` arguments may begin.
+    fn enter_function(&self, name: &str, arg_count: usize) -> VMResult<UserFunction> {
+        let function = self.functions.get(name).cloned()
             .ok_or_else(|| VMError::RuntimeError(format!("Unknown function: {}", name)))?;
         
-        if args.len() != params.len() {
+        if arg_count != function.params.len() {
             return Err(VMError::RuntimeError(format!(
                 "Function {} expects {} arguments, got {}",
-                name, params.len(), args.len()
+                name, function.params.len(), arg_count
             )));
         }
         
-        let old_vars = self.variables.clone();
-        
-        for (param, arg) in params.iter().zip(args.iter()) {
-            self.variables.insert(param.clone(), arg.clone());
+        if self.variables.depth() >= self.limits.max_call_depth {
+            return Err(VMError::StackOverflow(name.to_string()));
         }
-        
-        let result = self.evaluate_expression(&body);
-        
-        self.variables = old_vars;
-        result
+        if let Some(max) = self.limits.max_variables.filter(|&max| self.variables.len() + arg_count > max) {
+            return Err(VMError::QuotaExceeded(Quota::Variables(max)));
+        }
+        Ok(function)
     }
-}
\ No newline at end of file
+    
+    fn function_result(name: &str, result: VMResult<Flow>) -> VMResult<Value> {
+        match result? {
+            Flow::Value(value) | Flow::Return(value, _) => Ok(value),
+            Flow::Break(span) => {
+                Err(VMError::RuntimeError(format!("b used outside of a loop in function {}", name)).at(span))
+            }
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use std::sync::Arc;
+
+use crate::ast::expressions::BinaryOp;
+use crate::ast::span::{Position, Span};
+use crate::utils::error::{VMError, VMResult};
+use super::bytecode::{Chunk, FunctionProto, Instruction, Program};
+use super::constants::VM_VERSION;
+use super::value::Value;
+
+/// Binary module format for compiled programs:
+///
+/// ```text
+/// module   = "PIKO" string(VM_VERSION) count chunk*
+/// chunk    = span count string* count function* count instruction* span* uint*
+/// function = string count string* chunk
+/// span     = uint(offset) uint(line) uint(column) (start, then end)
+/// string   = count byte*
+/// ```
+///
+/// Counts and other integers are unsigned LEB128. An instruction is an
+/// opcode byte followed by its operands; the spans and fuel costs after
+/// the instructions belong to them one for one. Modules load only on a VM
+/// of the version they were written by.
+const MAGIC: &[u8; 4] = b"PIKO";
+
+/// Functions nest no deeper than this in a module, so a corrupt module
+/// cannot exhaust the stack while loading.
+const MAX_NESTING: usize = 64;
+
+/// The operand that stands for `op` in a `Binary` instruction. The match
+/// is exhaustive so a new operator cannot be added without a code here and
+/// in [`binary_op`].
+fn binary_op_code(op: BinaryOp) -> usize {
+    match op {
+        BinaryOp::Add => 0,
+        BinaryOp::Sub => 1,
+        BinaryOp::Mul => 2,
+        BinaryOp::Div => 3,
+        BinaryOp::Rem => 4,
+        BinaryOp::Pow => 5,
+        BinaryOp::Min => 6,
+        BinaryOp::Max => 7,
+        BinaryOp::Lt => 8,
+        BinaryOp::Gt => 9,
+        BinaryOp::Le => 10,
+        BinaryOp::Ge => 11,
+        BinaryOp::Eq => 12,
+        BinaryOp::Ne => 13,
+    }
+}
+
+fn binary_op(code: usize) -> Option<BinaryOp> {
+    let op = match code {
+        0 => BinaryOp::Add,
+        1 => BinaryOp::Sub,
+        2 => BinaryOp::Mul,
+        3 => BinaryOp::Div,
+        4 => BinaryOp::Rem,
+        5 => BinaryOp::Pow,
+        6 => BinaryOp::Min,
+        7 => BinaryOp::Max,
+        8 => BinaryOp::Lt,
+        9 => BinaryOp::Gt,
+        10 => BinaryOp::Le,
+        11 => BinaryOp::Ge,
+        12 => BinaryOp::Eq,
+        13 => BinaryOp::Ne,
+        _ => return None,
+    };
+    Some(op)
+}
+
+impl Program {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer { bytes: MAGIC.to_vec() };
+        writer.string(VM_VERSION);
+        writer.uint(self.forms.len());
+        for chunk in &self.forms {
+            writer.chunk(chunk);
+        }
+        writer.bytes
+    }
+    
+    /// Loads a module written by [`Program::to_bytes`], checking that it
+    /// was written by this VM version and that every operand is in range.
+    pub fn from_bytes(bytes: &[u8]) -> VMResult<Program> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a Piko module"));
+        }
+        let version = reader.string()?;
+        if version != VM_VERSION {
+            return Err(VMError::CompileError(format!(
+                "Module was compiled for VM version {}, but this is version {}",
+                version, VM_VERSION
+            )));
+        }
+        
+        let mut forms = Vec::new();
+        for _ in 0..reader.uint()? {
+            forms.push(reader.chunk(0)?);
+        }
+        if reader.pos != bytes.len() {
+            return Err(invalid("trailing bytes after the last form"));
+        }
+        Ok(Program { forms })
+    }
+}
+
+fn invalid(reason: &str) -> VMError {
+    VMError::CompileError(format!("Invalid module: {}", reason))
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn uint(&mut self, value: usize) {
+        let mut value = value as u64;
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.bytes.push(byte);
+                return;
+            }
+            self.bytes.push(byte | 0x80);
+        }
+    }
+    
+    fn string(&mut self, value: &str) {
+        self.uint(value.len());
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+    
+    fn span(&mut self, span: Span) {
+        for position in [span.start, span.end] {
+            self.uint(position.offset);
+            self.uint(position.line);
+            self.uint(position.column);
+        }
+    }
+    
+    fn chunk(&mut self, chunk: &Chunk) {
+        self.span(chunk.span);
+        self.uint(chunk.constants.len());
+        for constant in &chunk.constants {
+            self.string(constant);
+        }
+        self.uint(chunk.functions.len());
+        for function in &chunk.functions {
+            self.string(&function.name);
+            self.uint(function.params.len());
+            for param in function.params.iter() {
+                self.string(param);
+            }
+            self.chunk(&function.chunk);
+        }
+        self.uint(chunk.code.len());
+        for instruction in &chunk.code {
+            self.instruction(*instruction);
+        }
+        for span in &chunk.spans {
+            self.span(*span);
+        }
+        for &cost in &chunk.costs {
+            self.uint(cost as usize);
+        }
+    }
+    
+    fn instruction(&mut self, instruction: Instruction) {
+        let (opcode, operands): (u8, &[usize]) = match instruction {
+            Instruction::Push(index) => (0, &[index]),
+            Instruction::Load(index) => (1, &[index]),
+            Instruction::Store(index) => (2, &[index]),
+            Instruction::Pop(count) => (3, &[count]),
+            Instruction::Nip => (4, &[]),
+            Instruction::Dup => (5, &[]),
+            Instruction::Binary(op) => (6, &[binary_op_code(op)]),
+            Instruction::Not => (7, &[]),
+            Instruction::Jump(target) => (8, &[target]),
+            Instruction::JumpIfFalse(target) => (9, &[target]),
+            Instruction::Call(name, count) => (10, &[name, count]),
+            Instruction::Return => (11, &[]),
+            Instruction::Break => (12, &[]),
+            Instruction::Input(index) => (13, &[index]),
+            Instruction::Output => (14, &[]),
+            Instruction::Define(index) => (15, &[index]),
+        };
+        self.bytes.push(opcode);
+        for operand in operands {
+            self.uint(*operand);
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> VMResult<&'a [u8]> {
+        let end = self.pos.checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| invalid("unexpected end of data"))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+    
+    fn byte(&mut self) -> VMResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+    
+    fn uint(&mut self) -> VMResult<usize> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return usize::try_from(value).map_err(|_| invalid("integer out of range"));
+            }
+        }
+        Err(invalid("integer out of range"))
+    }
+    
+    fn string(&mut self) -> VMResult<String> {
+        let len = self.uint()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid("string is not UTF-8"))
+    }
+    
+    fn span(&mut self) -> VMResult<Span> {
+        let mut position = || -> VMResult<Position> {
+            Ok(Position { offset: self.uint()?, line: self.uint()?, column: self.uint()? })
+        };
+        let start = position()?;
+        let end = position()?;
+        Ok(Span { start, end })
+    }
+    
+    fn chunk(&mut self, depth: usize) -> VMResult<Chunk> {
+        if depth > MAX_NESTING {
+            return Err(invalid("functions nested too deeply"));
+        }
+        let span = self.span()?;
+        
+        let mut constants = Vec::new();
+        for _ in 0..self.uint()? {
+            constants.push(Value::from(self.string()?));
+        }
+        
+        let mut functions = Vec::new();
+        for _ in 0..self.uint()? {
+            let name = Value::from(self.string()?);
+            let mut params = Vec::new();
+            for _ in 0..self.uint()? {
+                params.push(Value::from(self.string()?));
+            }
+            let chunk = Arc::new(self.chunk(depth + 1)?);
+            functions.push(FunctionProto { name, params: params.into(), chunk });
+        }
+        
+        let count = self.uint()?;
+        let mut code = Vec::new();
+        for _ in 0..count {
+            code.push(self.instruction()?);
+        }
+        let mut spans = Vec::new();
+        for _ in 0..count {
+            spans.push(self.span()?);
+        }
+        let mut costs = Vec::new();
+        for _ in 0..count {
+            costs.push(self.uint()? as u64);
+        }
+        
+        let chunk = Chunk { code, spans, costs, constants, functions, span };
+        Self::validate(&chunk)?;
+        Ok(chunk)
+    }
+    
+    fn instruction(&mut self) -> VMResult<Instruction> {
+        let instruction = match self.byte()? {
+            0 => Instruction::Push(self.uint()?),
+            1 => Instruction::Load(self.uint()?),
+            2 => Instruction::Store(self.uint()?),
+            3 => Instruction::Pop(self.uint()?),
+            4 => Instruction::Nip,
+            5 => Instruction::Dup,
+            6 => Instruction::Binary(binary_op(self.uint()?).ok_or_else(|| invalid("unknown operator"))?),
+            7 => Instruction::Not,
+            8 => Instruction::Jump(self.uint()?),
+            9 => Instruction::JumpIfFalse(self.uint()?),
+            10 => Instruction::Call(self.uint()?, self.uint()?),
+            11 => Instruction::Return,
+            12 => Instruction::Break,
+            13 => Instruction::Input(self.uint()?),
+            14 => Instruction::Output,
+            15 => Instruction::Define(self.uint()?),
+            opcode => return Err(invalid(&format!("unknown opcode {}", opcode))),
+        };
+        Ok(instruction)
+    }
+    
+    /// Checks every operand refers to something in the chunk, so running a
+    /// loaded module can fail but never index out of bounds.
+    /// Checks that operands are in range, and that every jump back lands on
+    /// an instruction that costs fuel, so no loop can run without it.
+    fn validate(chunk: &Chunk) -> VMResult<()> {
+        for (at, instruction) in chunk.code.iter().enumerate() {
+            let in_range = match *instruction {
+                Instruction::Push(index) | Instruction::Load(index) | Instruction::Store(index)
+                | Instruction::Call(index, _) | Instruction::Input(index) => index < chunk.constants.len(),
+                Instruction::Define(index) => index < chunk.functions.len(),
+                Instruction::Jump(target) | Instruction::JumpIfFalse(target) => target <= chunk.code.len(),
+                _ => true,
+            };
+            if !in_range {
+                return Err(invalid("operand out of range"));
+            }
+            match *instruction {
+                Instruction::Jump(target) | Instruction::JumpIfFalse(target)
+                    if target <= at && chunk.costs[target] == 0 => return Err(invalid("loop without fuel cost")),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
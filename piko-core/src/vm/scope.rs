@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+
+use crate::utils::error::{VMError, VMResult};
+use super::value::Value;
+
+/// Variables of a running program: the globals, plus one frame of locals
+/// for each function call in progress.
+///
+/// A name resolves to a local of the innermost call, then to a global.
+/// Locals of the calling functions are never visible. Assigning writes the
+/// local if there is one, otherwise an existing global, and otherwise
+/// creates a local, so functions can update globals but keep their own
+/// temporaries to themselves.
+#[derive(Debug, Default)]
+pub struct Scopes {
+    globals: HashMap<Value, Value>,
+    frames: Vec<HashMap<Value, Value>>,
+    count: usize,
+}
+
+impl Scopes {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.frames.last()
+            .and_then(|frame| frame.get(name))
+            .or_else(|| self.globals.get(name))
+    }
+    
+    /// Assigns a variable. The name is only copied when the variable is
+    /// created.
+    pub fn assign(&mut self, name: &str, value: Value) {
+        let scope = match self.frames.last_mut() {
+            Some(frame) if frame.contains_key(name) || !self.globals.contains_key(name) => frame,
+            _ => &mut self.globals,
+        };
+        match scope.get_mut(name) {
+            Some(slot) => *slot = value,
+            None => {
+                scope.insert(Value::from(name), value);
+                self.count += 1;
+            }
+        }
+    }
+    
+    /// Whether assigning `name` would create a variable rather than
+    /// update one.
+    pub fn creates(&self, name: &str) -> bool {
+        self.get(name).is_none()
+    }
+    
+    /// How many variables exist across the globals and all frames.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+    
+    /// Removes the variable `name` currently resolves to.
+    pub fn remove(&mut self, name: &str) -> Option<Value> {
+        let removed = self.frames.last_mut()
+            .and_then(|frame| frame.remove(name))
+            .or_else(|| self.globals.remove(name));
+        if removed.is_some() {
+            self.count -= 1;
+        }
+        removed
+    }
+    
+    /// The variables visible from the innermost call, locals hiding globals.
+    pub fn visible(&self) -> HashMap<&str, &str> {
+        self.globals.iter()
+            .chain(self.frames.last().into_iter().flatten())
+            .map(|(name, value)| (&**name, &**value))
+            .collect()
+    }
+    
+    /// How many function calls are in progress.
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+    
+    /// Enters a function call whose locals start out as its parameters.
+    pub fn push_frame(&mut self, locals: HashMap<Value, Value>) {
+        self.count += locals.len();
+        self.frames.push(locals);
+    }
+    
+    pub fn pop_frame(&mut self) -> VMResult<()> {
+        let frame = self.frames.pop().ok_or(VMError::StackUnderflow)?;
+        self.count -= frame.len();
+        Ok(())
+    }
+}
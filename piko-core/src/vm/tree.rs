@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+use crate::ast::expressions::{self, BinaryOp, Expression};
+use crate::ast::span::Span;
+use super::value::{Interner, Value};
+
+/// A form lowered for the tree-walking engine. It mirrors the syntax tree,
+/// but names and literals are interned once, when the form is lowered,
+/// rather than every time they are evaluated, and function bodies are
+/// shared rather than copied each time their definition runs.
+pub(super) struct Node {
+    pub node: Expr,
+    pub span: Span,
+}
+
+pub(super) enum Expr {
+    Variable(Value),
+    Literal(Value),
+    BinaryOp(Box<Node>, BinaryOp, Box<Node>),
+    Output(Box<Node>),
+    Input(Value),
+    Assign(Value, Box<Node>),
+    Return(Box<Node>),
+    Call(Value, Vec<Node>),
+    Function(Function),
+    Loop(Option<Box<Node>>, Box<Node>),
+    If(Box<Node>, Box<Node>, Option<Box<Node>>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+    Break,
+    ChainedOp(Vec<ChainOp>),
+    Block(Vec<Node>),
+}
+
+pub(super) enum ChainOp {
+    Input(Value),
+    Output,
+    Assign(Value, Box<Node>),
+    Return(Box<Node>),
+    Call(Value, Vec<Node>),
+    Function(Function),
+    Loop(Option<Box<Node>>, Box<Node>),
+    Break,
+}
+
+/// A function definition, ready to be bound to its name.
+pub(super) struct Function {
+    pub name: Value,
+    pub params: Arc<[Value]>,
+    pub body: Arc<Node>,
+}
+
+/// Lowers syntax trees, interning their text as it goes. Text is shared
+/// among the forms one lowering produces, and the table goes away with
+/// it, so a long-lived VM keeps only what the functions it defined use.
+#[derive(Default)]
+pub(super) struct Lowering {
+    interner: Interner,
+}
+
+impl Lowering {
+    pub fn node(&mut self, node: &expressions::Node) -> Node {
+        let expr = match &node.node {
+            Expression::Variable(name) => Expr::Variable(self.interner.intern(name)),
+            Expression::Literal(value) => Expr::Literal(self.interner.intern(value)),
+            Expression::BinaryOp(left, op, right) => Expr::BinaryOp(self.boxed(left), *op, self.boxed(right)),
+            Expression::Output(expr) => Expr::Output(self.boxed(expr)),
+            Expression::Input(var) => Expr::Input(self.interner.intern(var)),
+            Expression::Assign(var, expr) => Expr::Assign(self.interner.intern(var), self.boxed(expr)),
+            Expression::Return(expr) => Expr::Return(self.boxed(expr)),
+            Expression::Call(name, args) => Expr::Call(self.interner.intern(name), self.nodes(args)),
+            Expression::Function(name, params, body) => Expr::Function(self.function(name, params, body)),
+            Expression::Loop(condition, body) => Expr::Loop(self.optional(condition), self.boxed(body)),
+            Expression::If(condition, then_branch, else_branch) => {
+                Expr::If(self.boxed(condition), self.boxed(then_branch), self.optional(else_branch))
+            }
+            Expression::And(left, right) => Expr::And(self.boxed(left), self.boxed(right)),
+            Expression::Or(left, right) => Expr::Or(self.boxed(left), self.boxed(right)),
+            Expression::Not(expr) => Expr::Not(self.boxed(expr)),
+            Expression::Break => Expr::Break,
+            Expression::ChainedOp(ops) => Expr::ChainedOp(ops.iter().map(|op| self.chain_op(op)).collect()),
+            Expression::Block(exprs) => Expr::Block(self.nodes(exprs)),
+        };
+        Node { node: expr, span: node.span }
+    }
+    
+    fn chain_op(&mut self, op: &expressions::ChainOp) -> ChainOp {
+        match op {
+            expressions::ChainOp::Input(var) => ChainOp::Input(self.interner.intern(var)),
+            expressions::ChainOp::Output => ChainOp::Output,
+            expressions::ChainOp::Assign(var, expr) => ChainOp::Assign(self.interner.intern(var), self.boxed(expr)),
+            expressions::ChainOp::Return(expr) => ChainOp::Return(self.boxed(expr)),
+            expressions::ChainOp::Call(name, args) => ChainOp::Call(self.interner.intern(name), self.nodes(args)),
+            expressions::ChainOp::Function(name, params, body) => ChainOp::Function(self.function(name, params, body)),
+            expressions::ChainOp::Loop(condition, body) => ChainOp::Loop(self.optional(condition), self.boxed(body)),
+            expressions::ChainOp::Break => ChainOp::Break,
+        }
+    }
+    
+    fn function(&mut self, name: &str, params: &[String], body: &expressions::Node) -> Function {
+        Function {
+            name: self.interner.intern(name),
+            params: params.iter().map(|param| self.interner.intern(param)).collect(),
+            body: Arc::new(self.node(body)),
+        }
+    }
+    
+    fn boxed(&mut self, node: &expressions::Node) -> Box<Node> {
+        Box::new(self.node(node))
+    }
+    
+    fn optional(&mut self, node: &Option<Box<expressions::Node>>) -> Option<Box<Node>> {
+        node.as_deref().map(|node| self.boxed(node))
+    }
+    
+    fn nodes(&mut self, nodes: &[expressions::Node]) -> Vec<Node> {
+        nodes.iter().map(|node| self.node(node)).collect()
+    }
+}
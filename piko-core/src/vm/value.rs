@@ -0,0 +1,24 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// A Piko value. Values are immutable, so copies share one allocation.
+pub type Value = Arc<str>;
+
+/// Shares one allocation between every use of the same identifier or
+/// literal. Only text from programs is interned, so it stays as small as
+/// the source being run.
+#[derive(Debug, Default)]
+pub struct Interner {
+    strings: HashSet<Value>,
+}
+
+impl Interner {
+    pub fn intern(&mut self, text: &str) -> Value {
+        if let Some(value) = self.strings.get(text) {
+            return value.clone();
+        }
+        let value = Value::from(text);
+        self.strings.insert(value.clone());
+        value
+    }
+}
@@ -0,0 +1,104 @@
+use piko_core::utils::base_26;
+
+fn to_u128(s: &str) -> u128 {
+    s.bytes().fold(0, |acc, c| acc * 26 + (c - b'a' + 1) as u128)
+}
+
+fn from_u128(mut n: u128) -> String {
+    let mut digits = Vec::new();
+    while n > 0 {
+        n -= 1;
+        digits.push(b'a' + (n % 26) as u8);
+        n /= 26;
+    }
+    digits.reverse();
+    String::from_utf8(digits).unwrap()
+}
+
+#[test]
+fn test_matches_native_arithmetic() {
+    let values = ["a", "b", "z", "aa", "az", "zz", "hello", "world", "piko", "zzzzzzzzzzzz", "abcdefghijklm"];
+    for left in values {
+        for right in values {
+            let (l, r) = (to_u128(left), to_u128(right));
+            assert_eq!(base_26::add(left, right), from_u128(l + r));
+            assert_eq!(base_26::sub(left, right), from_u128(l.saturating_sub(r).max(1)));
+            assert_eq!(base_26::mul(left, right), from_u128(l * r));
+            assert_eq!(base_26::div(left, right), from_u128((l / r).max(1)));
+            assert_eq!(base_26::rem(left, right), from_u128((l - 1) % r + 1));
+            assert_eq!(base_26::min(left, right), from_u128(l.min(r)));
+            assert_eq!(base_26::max(left, right), from_u128(l.max(r)));
+            assert_eq!(base_26::compare_lt(left, right), l < r);
+            assert_eq!(base_26::compare_eq(left, right), l == r);
+        }
+    }
+}
+
+#[test]
+fn test_small_and_large_values_agree() {
+    // Thirteen letters is the most that arithmetic does without a BigUint.
+    let pairs = [
+        ("zzzzzzzzzzzzz", "zzzzzzzzzzzzz"),
+        ("zzzzzzzzzzzzz", "aaaaaaaaaaaaaa"),
+        ("aaaaaaaaaaaaaa", "zzzzzzzzzzzzz"),
+        ("aaaaaaaaaaaaaa", "b"),
+        ("zzzzzzzzzzzzz", "a"),
+    ];
+    for (left, right) in pairs {
+        let (l, r) = (to_u128(left), to_u128(right));
+        assert_eq!(base_26::add(left, right), from_u128(l + r));
+        assert_eq!(base_26::sub(left, right), from_u128(l.saturating_sub(r).max(1)));
+        assert_eq!(base_26::mul(left, right), from_u128(l * r));
+        assert_eq!(base_26::div(left, right), from_u128((l / r).max(1)));
+        assert_eq!(base_26::rem(left, right), from_u128((l - 1) % r + 1));
+        assert_eq!(base_26::compare_lt(left, right), l < r);
+        assert_eq!(base_26::compare_ge(left, right), l >= r);
+    }
+    assert_eq!(base_26::add("a-b", "c"), base_26::add("ab", "c"));
+    assert!(base_26::compare_eq("a b", "ab"));
+}
+
+#[test]
+fn test_arbitrary_length() {
+    let long = "z".repeat(40);
+    assert_eq!(base_26::add(&long, "a"), "a".repeat(41));
+    assert_eq!(base_26::sub(&"a".repeat(41), "a"), long);
+    
+    let word = "supercalifragilisticexpialidocious";
+    let other = "pneumonoultramicroscopicsilicovolcanoconiosis";
+    let product = base_26::mul(word, other);
+    assert_eq!(base_26::div(&product, other), word);
+    assert_eq!(base_26::div(&product, word), other);
+    assert_eq!(base_26::sub(&base_26::add(word, other), other), word);
+    
+    assert!(base_26::compare_lt(word, other));
+    assert!(base_26::compare_gt(&base_26::add(&long, "a"), &long));
+    assert!(base_26::compare_ne(&format!("{}a", long), &format!("{}b", long)));
+    assert_eq!(base_26::div(word, ""), "a");
+}
+
+#[test]
+fn test_rem_and_pow() {
+    assert_eq!(base_26::rem("piko", "z"), "o");
+    assert_eq!(base_26::rem("d", "b"), "b");
+    assert_eq!(base_26::rem("e", "b"), "a");
+    assert_eq!(base_26::rem("z", ""), "a");
+    
+    assert_eq!(base_26::pow("b", "j"), from_u128(1024));
+    assert_eq!(base_26::pow("z", "b"), "yz");
+    assert_eq!(base_26::pow("hello", "a"), "hello");
+    assert_eq!(base_26::pow("hello", ""), "a");
+    
+    let huge = base_26::pow("z", "cv");
+    assert_eq!(huge, format!("y{}z", "y".repeat(98)));
+    assert_eq!(base_26::div(&huge, &base_26::pow("z", "cu")), "z");
+}
+
+#[test]
+fn test_indices() {
+    for n in [0usize, 1, 26, 27, 702, 703, 123456] {
+        assert_eq!(base_26::from_index(n), from_u128(n as u128));
+        assert_eq!(base_26::to_index(&base_26::from_index(n)), Some(n));
+    }
+    assert_eq!(base_26::to_index(&"z".repeat(40)), None);
+}
@@ -0,0 +1,183 @@
+use std::io::Cursor;
+
+use piko_core::ast::span::Span;
+use piko_core::ast::{Parser, PikoAst};
+use piko_core::ast::expressions::Parseable;
+use piko_core::vm::bytecode::{Chunk, Instruction, Program};
+use piko_core::vm::compiler::Compiler;
+use piko_core::vm::{Engine, Limits, VM};
+use piko_core::VMError;
+
+const EXAMPLES: &[&str] = &[
+    include_str!("../../examples/hello.pyx"),
+    include_str!("../../examples/variables.pyx"),
+    include_str!("../../examples/math.pyx"),
+    include_str!("../../examples/functions.pyx"),
+    include_str!("../../examples/loops.pyx"),
+    include_str!("../../examples/input.pyx"),
+    include_str!("../../examples/chains.pyx"),
+];
+
+const PROGRAMS: &[&str] = &[
+    "(f greet (first last) (o first) (o last) (r (+ first last)))\n(o (c greet \"a\" \"b\"))",
+    "(f max (x y) (? (> x y) x y))\n(o (c max \"c\" \"e\"))\n(o (? (== \"a\" \"b\") \"yes\"))",
+    "(a x \"a\")\n(l (? (== x \"c\") (b) (a x (+ x \"a\"))))\n(o x)",
+    "(a n \"a\")\n(o (l (< n \"e\") (a n (+ n \"a\"))))\n(o (l (b)))\n(o (l (+ n (b))))",
+    "(f find (n) (l (? (== n \"e\") (r n)) (a n (+ n \"a\"))) \"never\")\n(o (c find \"a\"))",
+    "(f early (x) (o \"before\") (? (> x \"b\") (r \"big\")) (o \"after\") \"small\")\n(o (c early \"a\"))\n(o (c early \"z\"))",
+    "(a total \"a\")\n(f add (x) (a total (+ total x)) (a temp x))\n(c add \"b\")\n(o total)\n(o temp)",
+    "(f count (n) (? (> n \"a\") (+ (c count (- n \"a\")) \"a\") \"a\"))\n(o (c count \"t\"))",
+    "(a c \"a\")\n(a flag \"b\")\n(l (&& (<= c \"e\") flag) (? (== c \"c\") (a flag \"a\")) (a c (+ c \"a\")))\n(o c)",
+    "(o (|| (== \"a\" \"b\") \"fallback\"))\n(o (&& \"a\" (c missing)))\n(o (|| \"yes\" (c missing)))\n(o (! \"a\"))",
+    "(o (- \"z\" \"b\"))\n(o (/ \"z\" \"b\"))\n(o (>= \"a\" \"b\"))\n(o (!= \"a\" \"b\"))",
+    "(o (% \"piko\" \"z\"))\n(o (^ \"z\" \"b\"))\n(o (<? \"hello\" \"world\"))\n(o (>? \"hello\" \"world\"))",
+    "(o (c cat \"hello, \" \"piko\"))\n(o (c split \"a,b,c\" \",\" \"c\"))\n(c g \"you\")\n(c o (c len \"abc\"))",
+    "(ao x \"test\")\n(i name)\n(o name)\n(o x)",
+    "(a x \"hello world\")\n(o (+ x \"a\"))",
+    "(o \"before\")\n(b)",
+    "(o (r \"a\"))",
+    "(f broken () (b))\n(o (c broken))",
+    "(o (c nothing \"a\"))",
+    "(f one (x) x)\n(c one)",
+    "(f forever (n) (c forever n))\n(c forever \"a\")",
+    "(l (o \"tick\"))",
+];
+
+/// Runs a program under one engine, returning its output and how it ended.
+fn run(engine: Engine, source: &str) -> (String, Result<(), String>) {
+    let input = Cursor::new("piko\nsecond\nthird\n".to_string());
+    let limits = Limits { max_call_depth: 50, max_output_bytes: Some(200), ..Limits::default() };
+    let mut vm = VM::with_limits(Vec::new(), input, limits);
+    vm.set_engine(engine);
+    let result = vm.execute(PikoAst::parse(source).unwrap()).map_err(|e| e.to_string());
+    (String::from_utf8_lossy(vm.get_output()).to_string(), result)
+}
+
+#[test]
+fn test_engines_agree() {
+    for source in EXAMPLES.iter().chain(PROGRAMS) {
+        let tree = run(Engine::TreeWalking, source);
+        let bytecode = run(Engine::Bytecode, source);
+        assert_eq!(bytecode, tree, "engines disagree on:\n{}", source);
+    }
+}
+
+#[test]
+fn test_engines_take_the_same_steps() {
+    let run = |engine: Engine, source: &str, fuel: u64| {
+        let input = Cursor::new("piko\nsecond\nthird\n".to_string());
+        let limits = Limits { max_call_depth: 50, max_output_bytes: Some(200), ..Limits::default() };
+        let mut vm = VM::with_limits(Vec::new(), input, limits);
+        vm.set_engine(engine);
+        vm.set_fuel(Some(fuel));
+        let result = vm.execute(PikoAst::parse(source).unwrap()).map_err(|e| e.kind().to_string());
+        (String::from_utf8_lossy(vm.get_output()).to_string(), result, vm.steps())
+    };
+    for source in EXAMPLES.iter().chain(PROGRAMS) {
+        for fuel in (0..40).chain([100, 1000, 100_000]) {
+            let tree = run(Engine::TreeWalking, source, fuel);
+            let bytecode = run(Engine::Bytecode, source, fuel);
+            assert_eq!(bytecode, tree, "engines disagree with {} fuel on:\n{}", fuel, source);
+        }
+    }
+}
+
+#[test]
+fn test_disassemble() {
+    let ast = Parser::parse_program("(f double (x) (* x \"b\"))\n(l (< n \"e\") (a n (c double n)))").unwrap();
+    let program = Compiler::compile(&ast);
+    assert_eq!(program.forms.len(), 2);
+    assert_eq!(program.disassemble(), "\
+form 0 at line 1, column 1:
+     0  define double
+function double (x) at line 1, column 15:
+     0  load x
+     1  push \"b\"
+     2  op *
+form 1 at line 2, column 1:
+     0  push \"a\"
+     1  load n
+     2  push \"e\"
+     3  op <
+     4  jump-if-false 10
+     5  load n
+     6  call double 1
+     7  store n
+     8  nip
+     9  jump 1
+");
+}
+
+#[test]
+fn test_shared_state() {
+    let mut vm = VM::new(Vec::new(), Cursor::new(String::new()));
+    vm.set_engine(Engine::Bytecode);
+    vm.execute(PikoAst::parse("(f twice (x) (+ x x))\n(a seed \"c\")").unwrap()).unwrap();
+    vm.set_engine(Engine::TreeWalking);
+    vm.execute(PikoAst::parse("(o (c twice seed))").unwrap()).unwrap();
+    assert_eq!(vm.call("twice", &["z"]).unwrap(), "az");
+    assert_eq!(String::from_utf8_lossy(vm.get_output()), "f\n");
+}
+
+#[test]
+fn test_stack_underflow() {
+    let chunk = Chunk {
+        code: vec![Instruction::Pop(1)],
+        spans: vec![Span::default()],
+        costs: vec![1],
+        constants: Vec::new(),
+        functions: Vec::new(),
+        span: Span::default(),
+    };
+    let mut vm = VM::new(Vec::new(), Cursor::new(String::new()));
+    let error = vm.execute_compiled(&Program { forms: vec![chunk] }).unwrap_err();
+    assert!(matches!(error.kind(), VMError::StackUnderflow));
+}
+
+#[test]
+fn test_module_round_trip() {
+    for source in EXAMPLES.iter().chain(PROGRAMS) {
+        let program = Compiler::compile(&Parser::parse_program(source).unwrap());
+        let loaded = Program::from_bytes(&program.to_bytes()).unwrap();
+        assert_eq!(loaded, program);
+        
+        let input = Cursor::new("piko\nsecond\nthird\n".to_string());
+        let limits = Limits { max_call_depth: 50, max_output_bytes: Some(200), ..Limits::default() };
+        let mut vm = VM::with_limits(Vec::new(), input, limits);
+        let result = vm.execute_compiled(&loaded).map_err(|e| e.to_string());
+        let (output, expected) = run(Engine::Bytecode, source);
+        assert_eq!(result, expected);
+        assert_eq!(String::from_utf8_lossy(vm.get_output()), output);
+    }
+}
+
+#[test]
+fn test_module_rejects_bad_input() {
+    let message = |bytes: &[u8]| Program::from_bytes(bytes).unwrap_err().to_string();
+    
+    let mut old = b"PIKO".to_vec();
+    old.extend_from_slice(&[3, b'0', b'.', b'9', 0]);
+    assert_eq!(message(&old), "Compile error: Module was compiled for VM version 0.9, but this is version 1.1");
+    assert_eq!(message(b"PYKO\x03"), "Compile error: Invalid module: not a Piko module");
+    
+    let bytes = Compiler::compile(&Parser::parse_program("(o (+ \"a\" \"b\"))").unwrap()).to_bytes();
+    assert!(message(&bytes[..bytes.len() - 1]).contains("unexpected end of data"));
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert!(message(&trailing).contains("trailing bytes"));
+    
+    let chunk = Chunk {
+        code: vec![Instruction::Push(3)],
+        spans: vec![Span::default()],
+        costs: vec![1],
+        constants: vec!["a".into()],
+        functions: Vec::new(),
+        span: Span::default(),
+    };
+    let bytes = Program { forms: vec![chunk] }.to_bytes();
+    assert!(message(&bytes).contains("operand out of range"));
+    
+    let mut program = Compiler::compile(&Parser::parse_program("(l (o \"x\"))").unwrap());
+    program.forms[0].costs.iter_mut().for_each(|cost| *cost = 0);
+    assert!(message(&program.to_bytes()).contains("loop without fuel cost"));
+}
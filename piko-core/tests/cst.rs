@@ -0,0 +1,50 @@
+use piko_core::ast::{Cst, CstNode, Parser};
+use piko_core::ast::lexer::TriviaKind;
+
+const SOURCE: &str = "# greeting helpers\n\n(f greet name   # who to greet\n    (o name))\n\n\n(c greet \"piko\")  \n# end\n";
+
+#[test]
+fn test_lossless_round_trip() {
+    let cst = Cst::parse(SOURCE).unwrap();
+    assert_eq!(cst.to_string(), SOURCE);
+    assert_eq!(cst.forms.len(), 2);
+    
+    for source in [include_str!("../../examples/loops.pyx"), include_str!("../../examples/functions.pyx"), ""] {
+        assert_eq!(Cst::parse(source).unwrap().to_string(), source);
+    }
+}
+
+#[test]
+fn test_trivia() {
+    let cst = Cst::parse(SOURCE).unwrap();
+    
+    let comments: Vec<&str> = cst.forms[0].comments().map(|c| c.text).collect();
+    assert_eq!(comments, vec!["# greeting helpers"]);
+    
+    let blank = cst.forms[1].leading().iter()
+        .any(|t| t.kind == TriviaKind::Whitespace && t.text.matches('\n').count() > 1);
+    assert!(blank);
+    
+    match &cst.forms[0] {
+        CstNode::List(list) => {
+            let body: Vec<&str> = list.children[3].comments().map(|c| c.text).collect();
+            assert_eq!(body, vec!["# who to greet"]);
+        }
+        CstNode::Token(_) => panic!("expected a list"),
+    }
+    
+    assert_eq!(cst.trailing.iter().map(|t| t.text).collect::<String>(), "  \n# end\n");
+}
+
+#[test]
+fn test_cst_to_ast() {
+    let cst = Cst::parse(SOURCE).unwrap();
+    assert_eq!(cst.to_ast().unwrap(), Parser::parse_program(SOURCE).unwrap());
+    
+    let node = cst.forms[1].to_node().unwrap();
+    assert_eq!((node.span.start.line, node.span.start.column), (7, 1));
+    
+    assert!(Cst::parse("(o x").is_err());
+    assert!(Cst::parse("(o x))").is_err());
+    assert!(Cst::parse("(o x y)").unwrap().to_ast().is_err());
+}
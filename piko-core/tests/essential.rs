@@ -3,7 +3,8 @@ use std::io::Cursor;
 
 use piko_core::ast::expressions::Parseable;
 use piko_core::ast::PikoAst;
-use piko_core::vm::VM;
+use piko_core::vm::{Arity, Engine, Limits, VM};
+use piko_core::{Quota, VMError};
 
 #[test]
 fn test_basic_functionality() {
@@ -45,4 +46,474 @@ fn test_chain_operations() {
     
     let ast = PikoAst::parse("(ao x \"test\")").unwrap();
     assert!(vm.execute(ast).is_ok());
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_multi_statement_functions() {
+    let output = Vec::new();
+    let input = Cursor::new(String::new());
+    let mut vm = VM::new(output, input);
+    
+    let ast = PikoAst::parse("
+        (f greet (first last)
+            (o first)
+            (o last)
+            (+ first last))
+        (f hello () (o \"hello\"))
+        (o (c greet \"a\" \"b\"))
+        (c hello)
+    ").unwrap();
+    assert!(vm.execute(ast).is_ok());
+    assert_eq!(String::from_utf8_lossy(vm.get_output()), "a\nb\nc\nhello\n");
+    
+    assert!(PikoAst::parse("(f broken (x \"y\") (o x))").is_err());
+    assert!(PikoAst::parse("(f broken (x))").is_err());
+}
+
+#[test]
+fn test_conditionals() {
+    let output = Vec::new();
+    let input = Cursor::new(String::new());
+    let mut vm = VM::new(output, input);
+    
+    let ast = PikoAst::parse("
+        (f max (x y) (? (> x y) x y))
+        (o (c max \"c\" \"e\"))
+        (o (c max \"z\" \"e\"))
+        (o (? (== \"a\" \"b\") \"yes\"))
+        (a x \"a\")
+        (l (? (== x \"c\") (b) (a x (+ x \"a\"))))
+        (o x)
+    ").unwrap();
+    assert!(vm.execute(ast).is_ok());
+    assert_eq!(String::from_utf8_lossy(vm.get_output()), "e\nz\na\nc\n");
+    
+    assert!(PikoAst::parse("(? x)").is_err());
+    assert!(PikoAst::parse("(? x y z w)").is_err());
+}
+
+#[test]
+fn test_non_local_return() {
+    let output = Vec::new();
+    let input = Cursor::new(String::new());
+    let mut vm = VM::new(output, input);
+    
+    let ast = PikoAst::parse("
+        (f find (limit)
+            (a x \"a\")
+            (l (!= x \"z\")
+                (a x (+ x \"a\"))
+                (? (== x limit) (ao found x) (o x))
+                (? (== x limit) (r x)))
+            (o \"unreachable\"))
+        (o (c find \"d\"))
+        (f early () (ar y \"c\" y) (o \"unreachable\"))
+        (o (c early))
+    ").unwrap();
+    assert!(vm.execute(ast).is_ok());
+    assert_eq!(String::from_utf8_lossy(vm.get_output()), "b\nc\nd\nd\nc\n");
+    
+    let error = vm.execute(PikoAst::parse("(o \"a\")\n(r \"b\")").unwrap()).unwrap_err();
+    assert!(error.to_string().contains("r used outside of a function"));
+    assert_eq!(error.span().unwrap().start.line, 2);
+}
+
+#[test]
+fn test_typed_control_flow() {
+    let output = Vec::new();
+    let input = Cursor::new(String::new());
+    let mut vm = VM::new(output, input);
+    
+    let ast = PikoAst::parse("
+        (a word \"break\")
+        (a x \"a\")
+        (l (< x \"c\") (o word) (a x (+ x \"a\")))
+        (a y \"a\")
+        (o (l (a y (+ y \"a\")) (? (== y \"d\") (ab z y) y)))
+        (o (f named () (o \"hi\")))
+    ").unwrap();
+    assert!(vm.execute(ast).is_ok());
+    assert_eq!(String::from_utf8_lossy(vm.get_output()), "break\nbreak\nc\nnamed\n");
+    
+    let error = vm.execute(PikoAst::parse("(b)").unwrap()).unwrap_err();
+    assert!(error.to_string().contains("b used outside of a loop"));
+    
+    let ast = PikoAst::parse("(f escape () (b))\n(l (c escape))").unwrap();
+    let error = vm.execute(ast).unwrap_err();
+    assert!(error.to_string().contains("b used outside of a loop in function escape"));
+}
+
+#[test]
+fn test_stray_control_flow_location() {
+    for engine in [Engine::TreeWalking, Engine::Bytecode] {
+        let mut vm = VM::new(Vec::new(), Cursor::new(String::new()));
+        vm.set_engine(engine);
+        
+        let error = vm.execute(PikoAst::parse("(o (+ (b) \"a\"))").unwrap()).unwrap_err();
+        let span = error.span().unwrap();
+        assert_eq!((span.start.line, span.start.column), (1, 7), "{:?}", engine);
+        
+        let error = vm.execute(PikoAst::parse("(a x \"a\")\n(o (r x))").unwrap()).unwrap_err();
+        let span = error.span().unwrap();
+        assert_eq!((span.start.line, span.start.column), (2, 4), "{:?}", engine);
+        
+        let ast = PikoAst::parse("(f escape ()\n  (o (b)))\n(l (c escape))").unwrap();
+        let error = vm.execute(ast).unwrap_err();
+        let span = error.span().unwrap();
+        assert_eq!((span.start.line, span.start.column), (2, 6), "{:?}", engine);
+    }
+}
+
+#[test]
+fn test_invalid_operands() {
+    let output = Vec::new();
+    let input = Cursor::new(String::new());
+    let mut vm = VM::new(output, input);
+    
+    let ast = PikoAst::parse("(a x \"hello world\")\n(o (+ x \"a\"))").unwrap();
+    let error = vm.execute(ast).unwrap_err();
+    assert!(matches!(error.kind(), VMError::InvalidOperation(_)));
+    assert_eq!(error.to_string(), "Invalid operation: + needs base-26 operands (a-z), but \"hello world\" contains ' ' at line 2, column 4");
+    
+    let error = vm.execute(PikoAst::parse("(< \"b\" \"Zed\")").unwrap()).unwrap_err();
+    assert!(error.to_string().contains("contains 'Z'"));
+    
+    let ast = PikoAst::parse("(o (== \"\" \"\"))").unwrap();
+    assert!(vm.execute(ast).is_ok());
+}
+
+#[test]
+fn test_extended_arithmetic() {
+    let output = Vec::new();
+    let input = Cursor::new(String::new());
+    let mut vm = VM::new(output, input);
+    
+    let ast = PikoAst::parse("
+        (o (% \"piko\" \"z\"))
+        (o (^ \"z\" \"b\"))
+        (o (<? \"hello\" \"world\"))
+        (o (>? \"hello\" \"world\"))
+        (a n \"a\")
+        (l (<= n \"d\") (o (? (== (% n \"b\") \"b\") \"even\" \"odd\")) (a n (+ n \"a\")))
+    ").unwrap();
+    assert!(vm.execute(ast).is_ok());
+    assert_eq!(String::from_utf8_lossy(vm.get_output()), "o\nyz\nhello\nworld\nodd\neven\nodd\neven\n");
+    
+    let error = vm.execute(PikoAst::parse("(^ \"b\" \"X\")").unwrap()).unwrap_err();
+    assert!(error.to_string().contains("^ needs base-26 operands"));
+}
+
+#[test]
+fn test_logical_operators() {
+    let output = Vec::new();
+    let input = Cursor::new(String::new());
+    let mut vm = VM::new(output, input);
+    
+    let ast = PikoAst::parse("
+        (a counter \"a\")
+        (a flag \"b\")
+        (l (&& (<= counter \"e\") flag) (? (== counter \"c\") (a flag \"a\")) (a counter (+ counter \"a\")))
+        (o counter)
+        (o (|| (== \"a\" \"b\") \"fallback\"))
+        (o (&& \"a\" (c missing)))
+        (o (|| \"yes\" (c missing)))
+        (o (! (!= \"a\" \"b\")))
+        (o (! \"a\"))
+    ").unwrap();
+    assert!(vm.execute(ast).is_ok());
+    assert_eq!(String::from_utf8_lossy(vm.get_output()), "d\nfallback\na\nyes\na\nb\n");
+    
+    assert_eq!(PikoAst::parse("(&& x (! y))").unwrap().to_string(), "(&& x (! y))");
+    assert!(PikoAst::parse("(! x y)").is_err());
+    assert!(PikoAst::parse("(|| x)").is_err());
+}
+
+#[test]
+fn test_string_builtins() {
+    let output = Vec::new();
+    let input = Cursor::new(String::new());
+    let mut vm = VM::new(output, input);
+    
+    let ast = PikoAst::parse("
+        (a name \"piko\")
+        (o (c cat \"hello, \" name \"!\"))
+        (o (c len name))
+        (o (c len \"\"))
+        (o (c at name \"b\"))
+        (o (c sub \"hello world\" \"g\" \"e\"))
+        (o (c rev name))
+        (o (c split \"a,b,c\" \",\" \"c\"))
+        (o (c join \"-\" \"x\" \"y\" \"z\"))
+        (f len (s) \"mine\")
+        (o (c len name))
+    ").unwrap();
+    assert!(vm.execute(ast).is_ok());
+    assert_eq!(
+        String::from_utf8_lossy(vm.get_output()),
+        "hello, piko!\nd\n\ni\nworld\nokip\nc\nx-y-z\nmine\n"
+    );
+    
+    let error = vm.execute(PikoAst::parse("(c rev \"a\" \"b\")").unwrap()).unwrap_err();
+    assert_eq!(error.to_string(), "Runtime error: Function rev expects 1 arguments, got 2 at line 1, column 1");
+    let error = vm.execute(PikoAst::parse("(c at \"abc\" \"B\")").unwrap()).unwrap_err();
+    assert!(matches!(error.kind(), VMError::InvalidOperation(_)));
+    assert!(vm.execute(PikoAst::parse("(c split \"abc\" \"\" \"a\")").unwrap()).is_err());
+}
+
+#[test]
+fn test_native_functions() {
+    let output = Vec::new();
+    let input = Cursor::new("piko\n".to_string());
+    let mut vm = VM::new(output, input);
+    
+    vm.register_native("shout", Arity::Exact(1), |_, args| Ok(args[0].to_uppercase()));
+    vm.register_native("count", Arity::AtLeast(0), |vm, args| {
+        vm.register_native("counted", Arity::Exact(0), |_, _| Ok("yes".to_string()));
+        Ok(args.len().to_string())
+    });
+    
+    let ast = PikoAst::parse("
+        (o (c shout \"hi\"))
+        (o (c count \"a\" \"b\" \"c\"))
+        (o (c counted))
+        (c g (c i))
+        (c o \"done\")
+    ").unwrap();
+    assert!(vm.execute(ast).is_ok());
+    assert_eq!(String::from_utf8_lossy(vm.get_output()), "HI\n3\nyes\nhello piko\ndone\n");
+    
+    let error = vm.execute(PikoAst::parse("(c shout)").unwrap()).unwrap_err();
+    assert_eq!(error.to_string(), "Runtime error: Function shout expects 1 arguments, got 0 at line 1, column 1");
+    
+    vm.register_native("fail", Arity::Exact(0), |_, _| Err(VMError::ExecutionError("host failure".to_string())));
+    let error = vm.execute(PikoAst::parse("\n(o (c fail))").unwrap()).unwrap_err();
+    assert_eq!(error.to_string(), "Execution error: host failure at line 2, column 4");
+}
+
+#[test]
+fn test_vm_moves_between_threads() {
+    let mut vm = VM::new(Vec::new(), Cursor::new(String::new()));
+    vm.register_native("shout", Arity::Exact(1), |_, args| Ok(args[0].to_uppercase()));
+    vm.execute(PikoAst::parse("(f twice (x) (+ x x))").unwrap()).unwrap();
+    let output = std::thread::spawn(move || {
+        vm.execute(PikoAst::parse("(o (c shout (c twice \"m\")))").unwrap()).unwrap();
+        String::from_utf8_lossy(vm.get_output()).to_string()
+    });
+    assert_eq!(output.join().unwrap(), "Z\n");
+}
+
+#[test]
+fn test_host_api() {
+    let output = Vec::new();
+    let input = Cursor::new(String::new());
+    let mut vm = VM::new(output, input);
+    
+    vm.set_variable("base", "b");
+    let ast = PikoAst::parse("
+        (f scale (x) (* x base))
+        (a result (c scale \"c\"))
+    ").unwrap();
+    assert!(vm.execute(ast).is_ok());
+    assert_eq!(vm.get_variable("result"), Some("f"));
+    assert_eq!(vm.variables(), vec![("base", "b"), ("result", "f")]);
+    
+    assert_eq!(vm.remove_variable("result"), Some("f".to_string()));
+    assert_eq!(vm.get_variable("result"), None);
+    
+    assert!(vm.has_function("scale"));
+    assert!(vm.has_function("cat"));
+    assert!(!vm.has_function("missing"));
+    let functions = vm.functions();
+    assert!(functions.contains(&("scale", Arity::Exact(1))));
+    assert!(functions.contains(&("join", Arity::AtLeast(1))));
+    
+    assert_eq!(vm.call("scale", &["z"]).unwrap(), "az");
+    assert_eq!(vm.call("rev", &["abc"]).unwrap(), "cba");
+    assert!(vm.call("scale", &[]).is_err());
+    assert!(vm.call("missing", &["a"]).is_err());
+}
+
+#[test]
+fn test_scoping() {
+    let output = Vec::new();
+    let input = Cursor::new(String::new());
+    let mut vm = VM::new(output, input);
+    
+    let ast = PikoAst::parse("
+        (a total \"a\")
+        (f add (x) (a total (+ total x)) (a temp x))
+        (c add \"b\")
+        (c add \"c\")
+        (o total)
+        (o temp)
+        (f inner () (o secret))
+        (f outer () (a secret \"hidden\") (c inner))
+        (c outer)
+        (f count (n) (? (> n \"a\") (+ (c count (- n \"a\")) \"a\") \"a\"))
+        (o (c count \"t\"))
+    ").unwrap();
+    assert!(vm.execute(ast).is_ok());
+    assert_eq!(String::from_utf8_lossy(vm.get_output()), "f\ntemp\nsecret\nt\n");
+    assert_eq!(vm.get_variable("temp"), None);
+    assert_eq!(vm.get_variable("secret"), None);
+}
+
+#[test]
+fn test_call_depth_limit() {
+    let output = Vec::new();
+    let input = Cursor::new(String::new());
+    let mut vm = VM::with_limits(output, input, Limits { max_call_depth: 30, ..Limits::default() });
+    
+    let ast = PikoAst::parse("
+        (f count (n) (? (> n \"a\") (+ (c count (- n \"a\")) \"a\") \"a\"))
+        (o (c count \"ad\"))
+    ").unwrap();
+    assert!(vm.execute(ast).is_ok());
+    
+    let error = vm.execute(PikoAst::parse("(o (c count \"ae\"))").unwrap()).unwrap_err();
+    assert!(matches!(error.kind(), VMError::StackOverflow(name) if name == "count"));
+    assert_eq!(error.to_string(), "Stack overflow: too many nested calls in function count at line 2, column 38");
+    
+    vm.set_limits(Limits { max_call_depth: 40, ..Limits::default() });
+    assert!(vm.execute(PikoAst::parse("(o (c count \"ae\"))").unwrap()).is_ok());
+    assert_eq!(String::from_utf8_lossy(vm.get_output()), "ad\nae\n");
+}
+
+#[test]
+fn test_default_limits_fit_test_stack() {
+    let nested = |template: &str, depth: usize| {
+        (0..depth).fold("(c forever n)".to_string(), |inner, _| template.replace('X', &inner))
+    };
+    let bodies = [
+        "(c forever n)".to_string(),
+        "(l (? (== n n) (&& n (o (+ (c forever n) \"a\")))))".to_string(),
+        nested("(ao x X)", 120),
+        nested("(l \"b\" (a y X) (b))", 60),
+    ];
+    for engine in [Engine::TreeWalking, Engine::Bytecode] {
+        for body in &bodies {
+            let mut vm = VM::new(Vec::new(), Cursor::new(String::new()));
+            vm.set_engine(engine);
+            let source = format!("(f forever (n) {})\n(c forever \"b\")", body);
+            let error = vm.execute(PikoAst::parse(&source).unwrap()).unwrap_err();
+            assert!(matches!(error.kind(), VMError::StackOverflow(name) if name == "forever"), "{}", error);
+        }
+        
+        let mut vm = VM::new(Vec::new(), Cursor::new(String::new()));
+        vm.set_engine(engine);
+        let source = (0..255).fold("\"a\"".to_string(), |inner, _| format!("(ao x {})", inner));
+        assert!(vm.execute(PikoAst::parse(&source).unwrap()).is_ok());
+    }
+}
+
+#[test]
+fn test_nesting_limit() {
+    let mut vm = VM::with_limits(Vec::new(), Cursor::new(String::new()), Limits { max_nesting: 8, ..Limits::default() });
+    assert!(vm.execute(PikoAst::parse("(o (+ (+ \"a\" \"a\") \"a\"))").unwrap()).is_ok());
+    
+    let error = vm.execute(PikoAst::parse("(o (+ (+ (+ (+ (+ (+ (+ (+ \"a\" \"a\") \"a\") \"a\") \"a\") \"a\") \"a\") \"a\") \"a\"))").unwrap()).unwrap_err();
+    assert!(matches!(error.kind(), VMError::StackOverflow(name) if name.is_empty()));
+    assert_eq!(error.to_string(), "Stack overflow: expressions nested too deeply at line 1, column 25");
+    
+    let ast = PikoAst::parse("(f deep (n) (o (+ (+ (+ (+ (+ (+ (+ n n) n) n) n) n) n) n)))\n(c deep \"a\")").unwrap();
+    let error = vm.execute(ast).unwrap_err();
+    assert!(matches!(error.kind(), VMError::StackOverflow(name) if name == "deep"));
+}
+
+#[test]
+fn test_fuel() {
+    let output = Vec::new();
+    let input = Cursor::new(String::new());
+    let mut vm = VM::new(output, input);
+    
+    vm.set_fuel(Some(1000));
+    let error = vm.execute(PikoAst::parse("(l (o \"x\"))").unwrap()).unwrap_err();
+    assert!(matches!(error.kind(), VMError::OutOfFuel(1000)));
+    assert!(error.to_string().starts_with("Out of fuel after 1000 steps"));
+    assert_eq!(vm.remaining_fuel(), Some(0));
+    
+    vm.add_fuel(10);
+    assert!(vm.execute(PikoAst::parse("(a x \"b\")").unwrap()).is_ok());
+    assert_eq!(vm.get_variable("x"), Some("b"));
+    assert_eq!(vm.remaining_fuel(), Some(8));
+    assert_eq!(vm.steps(), 1002);
+    
+    vm.set_fuel(None);
+    vm.add_fuel(10);
+    assert_eq!(vm.remaining_fuel(), None);
+    let ast = PikoAst::parse("(a n \"a\")\n(l (< n \"cv\") (a n (+ n \"a\")))").unwrap();
+    assert!(vm.execute(ast).is_ok());
+    assert_eq!(vm.get_variable("n"), Some("cv"));
+}
+
+#[test]
+fn test_fuel_grows_with_value_length() {
+    for engine in [Engine::TreeWalking, Engine::Bytecode] {
+        let mut vm = VM::new(Vec::new(), Cursor::new(String::new()));
+        vm.set_engine(engine);
+        vm.set_fuel(Some(1_000_000));
+        let error = vm.execute(PikoAst::parse("(a x \"zz\")\n(l (a x (* x x)))").unwrap()).unwrap_err();
+        assert!(matches!(error.kind(), VMError::OutOfFuel(1_000_000)));
+        assert!(vm.get_variable("x").unwrap().len() < 20_000);
+        
+        let mut vm = VM::new(Vec::new(), Cursor::new(String::new()));
+        vm.set_engine(engine);
+        vm.set_variable("x", &"z".repeat(1000));
+        assert!(vm.execute(PikoAst::parse("(a y (+ x x))").unwrap()).is_ok());
+        let long = vm.steps();
+        assert!(vm.execute(PikoAst::parse("(a y (+ \"z\" \"z\"))").unwrap()).is_ok());
+        assert!(long > 10_000 && vm.steps() - long < 10);
+        
+        for source in ["(a x (^ \"z\" \"zzzz\"))", "(a x (^ \"z\" \"zzz\"))"] {
+            let mut vm = VM::new(Vec::new(), Cursor::new(String::new()));
+            vm.set_engine(engine);
+            vm.set_fuel(Some(1000));
+            let error = vm.execute(PikoAst::parse(source).unwrap()).unwrap_err();
+            assert!(matches!(error.kind(), VMError::OutOfFuel(1000)), "{}", error);
+            assert_eq!(vm.get_variable("x"), None);
+        }
+        
+        let mut vm = VM::new(Vec::new(), Cursor::new(String::new()));
+        vm.set_engine(engine);
+        vm.set_fuel(Some(1000));
+        assert!(vm.execute(PikoAst::parse("(a x (^ \"b\" \"dd\"))").unwrap()).is_ok());
+        assert_eq!(vm.get_variable("x").map(str::len), Some(23));
+    }
+}
+
+#[test]
+fn test_quotas() {
+    let sandboxed = |limits: Limits, source: &str| {
+        let mut vm = VM::with_limits(Vec::new(), Cursor::new(String::new()), limits);
+        vm.execute(PikoAst::parse(source).unwrap())
+    };
+    let quota = |result: Result<(), VMError>| match result.unwrap_err().kind() {
+        VMError::QuotaExceeded(quota) => *quota,
+        error => panic!("expected a quota error, got {}", error),
+    };
+    
+    let limits = Limits { max_output_bytes: Some(10), ..Limits::default() };
+    assert!(sandboxed(limits.clone(), "(o \"four\")\n(o \"five\")").is_ok());
+    assert_eq!(quota(sandboxed(limits.clone(), "(l (o \"x\"))")), Quota::OutputBytes(10));
+    let mut vm = VM::with_limits(Vec::new(), Cursor::new(String::new()), limits);
+    assert!(vm.execute(PikoAst::parse("(o \"eight\")").unwrap()).is_ok());
+    assert!(vm.execute(PikoAst::parse("(o \"eight\")").unwrap()).is_err());
+    vm.reset_output_bytes();
+    assert!(vm.execute(PikoAst::parse("(o \"eight\")").unwrap()).is_ok());
+    
+    let limits = Limits { max_value_len: Some(50), ..Limits::default() };
+    assert_eq!(quota(sandboxed(limits.clone(), "(a x \"zz\")\n(l (a x (* x x)))")), Quota::ValueLength(50));
+    assert_eq!(quota(sandboxed(limits.clone(), "(o (^ \"zzzz\" \"zzzz\"))")), Quota::ValueLength(50));
+    assert_eq!(quota(sandboxed(limits.clone(), "(a x \"ab\")\n(l (a x (c cat x x)))")), Quota::ValueLength(50));
+    assert!(sandboxed(limits, "(o (^ \"b\" \"dd\"))").is_ok());
+    
+    let limits = Limits { max_variables: Some(3), ..Limits::default() };
+    assert!(sandboxed(limits.clone(), "(a x \"a\")\n(a y \"b\")\n(a x \"c\")\n(f id (v) v)\n(c id x)").is_ok());
+    assert_eq!(quota(sandboxed(limits.clone(), "(a x \"a\")\n(a y \"b\")\n(a z \"c\")\n(a w \"d\")")), Quota::Variables(3));
+    assert_eq!(quota(sandboxed(limits, "(f grow (n) (c grow n))\n(c grow \"a\")")), Quota::Variables(3));
+    
+    let limits = Limits { max_functions: Some(1), ..Limits::default() };
+    assert!(sandboxed(limits.clone(), "(f one () \"a\")\n(f one () \"b\")\n(c cat (c one))").is_ok());
+    let error = sandboxed(limits, "(f one () \"a\")\n(f two () \"b\")").unwrap_err();
+    assert_eq!(error.to_string(), "Quota exceeded: at most 1 functions may be defined at line 2, column 1");
+}
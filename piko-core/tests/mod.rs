@@ -1 +1,6 @@
 mod essential;
+mod parser;
+mod printer;
+mod cst;
+mod base_26;
+mod bytecode;
@@ -0,0 +1,169 @@
+use std::io::Cursor;
+
+use piko_core::ast::expressions::Parseable;
+use piko_core::ast::parser::MAX_NESTING;
+use piko_core::ast::{Cst, Parser, PikoAst, PikoExpression};
+use piko_core::vm::VM;
+use piko_core::VMError;
+
+#[test]
+fn test_multi_line_forms() {
+    let source = "
+        # adds two numbers
+        (f add x y  # params
+            (r (+ x y)))   # trailing comment
+        (a result (c add \"d\" \"f\"))
+        (o \"(not a paren\") (o result)
+    ";
+    
+    let ast = PikoAst::parse(source).unwrap();
+    match &ast {
+        PikoAst::Program(forms) => assert_eq!(forms.len(), 4),
+        _ => panic!("expected a program"),
+    }
+    
+    let mut vm = VM::new(Vec::new(), Cursor::new(String::new()));
+    vm.execute(ast).unwrap();
+    assert_eq!(String::from_utf8_lossy(vm.get_output()), "(not a paren\nj\n");
+}
+
+#[test]
+fn test_unbalanced_forms() {
+    assert!(PikoAst::parse("(o \"x\"").is_err());
+    assert!(PikoAst::parse("(o \"x\"))").is_err());
+    assert!(PikoAst::parse("(o \"x)").is_err());
+    assert!(PikoAst::parse("# only a comment").is_err());
+    
+    match Parser::parse_program("# only a comment\n").unwrap() {
+        PikoAst::Program(forms) => assert!(forms.is_empty()),
+        _ => panic!("expected a program"),
+    }
+}
+
+#[test]
+fn test_spans() {
+    let source = "(a x \"a\")\n(o\n  (+ x missing))";
+    let forms = match Parser::parse_program(source).unwrap() {
+        PikoAst::Program(forms) => forms,
+        _ => panic!("expected a program"),
+    };
+    
+    let output = match &forms[1] {
+        PikoAst::Expression(node) => node,
+        _ => panic!("expected an expression"),
+    };
+    assert_eq!((output.span.start.line, output.span.start.column), (2, 1));
+    assert_eq!((output.span.end.line, output.span.end.column), (3, 17));
+    match &output.node {
+        PikoExpression::Output(arg) => {
+            assert_eq!((arg.span.start.line, arg.span.start.column), (3, 3));
+        }
+        _ => panic!("expected an output"),
+    }
+    
+    let error = PikoAst::parse("(a x \"a\")\n(o x\n   \"y\")").unwrap_err();
+    let span = error.span().unwrap();
+    assert_eq!((span.start.line, span.start.column), (2, 1));
+    assert!(matches!(error.kind(), VMError::ParseError(_)));
+    
+    let error = PikoAst::parse("(o x)\n  (a \"x\" \"y\")").unwrap_err();
+    let span = error.span().unwrap();
+    assert_eq!((span.start.line, span.start.column), (2, 6));
+    
+    let error = PikoAst::parse("\n\n   (o x))").unwrap_err();
+    assert_eq!(error.span().unwrap().start.column, 9);
+    
+    let mut vm = VM::new(Vec::new(), Cursor::new(String::new()));
+    let error = vm.execute(PikoAst::parse("(o \"a\")\n(o (c nothing))").unwrap()).unwrap_err();
+    let span = error.span().unwrap();
+    assert_eq!((span.start.line, span.start.column), (2, 4));
+    assert!(error.to_string().ends_with("at line 2, column 4"));
+}
+
+#[test]
+fn test_grammar_diagnostics() {
+    let message = |source: &str| PikoAst::parse(source).unwrap_err().to_string();
+    
+    assert!(message("(o Name)").contains("Invalid identifier 'Name'"));
+    assert!(message("(o 42)").contains("Numbers are not allowed: '42'"));
+    assert!(message("(o 'x)").contains("Unexpected character '''"));
+    assert!(message("(o x . y)").contains("Unexpected character '.'"));
+    assert!(message("(o +)").contains("Operator '+' must be the first element"));
+    assert!(message("(=< x y)").contains("Unknown operator: =<"));
+    assert!(message("(\"o\" x)").contains("First element must be an operator"));
+    assert!(message("(o x)\n(o [x])").ends_with("at line 2, column 4"));
+}
+
+#[test]
+fn test_quoted_strings_are_literals() {
+    let mut vm = VM::new(Vec::new(), Cursor::new(String::new()));
+    vm.execute(PikoAst::parse("(a x \"world\")\n(o \"x\")\n(o x)").unwrap()).unwrap();
+    assert_eq!(String::from_utf8_lossy(vm.get_output()), "x\nworld\n");
+}
+
+#[test]
+fn test_recovering_parse() {
+    let source = "\
+(o \"first\")
+(o Bad)
+(a x)
+)
+(o \"second\")
+(l (o x)
+(o \"third\")
+";
+    let (ast, errors) = Parser::parse_program_recovering(source);
+    
+    let lines: Vec<usize> = errors.iter().map(|e| e.span().unwrap().start.line).collect();
+    assert_eq!(lines, vec![2, 3, 4, 6]);
+    assert!(errors[0].to_string().contains("Invalid identifier 'Bad'"));
+    assert!(errors[1].to_string().contains("a expects 2 arguments"));
+    assert!(errors[2].to_string().contains("Unexpected ')'"));
+    assert!(errors[3].to_string().contains("Unclosed '('"));
+    
+    let mut vm = VM::new(Vec::new(), Cursor::new(String::new()));
+    vm.execute(ast).unwrap();
+    assert_eq!(String::from_utf8_lossy(vm.get_output()), "first\nsecond\nthird\n");
+    
+    let (_, errors) = Parser::parse_program_recovering("(o \"fine\")\n");
+    assert!(errors.is_empty());
+}
+
+#[test]
+fn test_string_escapes() {
+    let node = Parser::parse_expression(r#""say \"hi\"\\n\tand\nbye \u{1F600}""#).unwrap();
+    assert_eq!(node.node, PikoExpression::Literal("say \"hi\"\\n\tand\nbye \u{1F600}".to_string()));
+    
+    let mut vm = VM::new(Vec::new(), Cursor::new(String::new()));
+    vm.execute(PikoAst::parse(r#"(o "line \"one\"\nline two")"#).unwrap()).unwrap();
+    assert_eq!(String::from_utf8_lossy(vm.get_output()), "line \"one\"\nline two\n");
+    
+    let error = PikoAst::parse("(o \"fine\")\n(o \"bad \\q escape\")").unwrap_err();
+    assert!(error.to_string().contains("Invalid escape sequence '\\q'"));
+    let span = error.span().unwrap();
+    assert_eq!((span.start.line, span.start.column), (2, 9));
+    
+    assert!(PikoAst::parse(r#"(o "\u{110000}")"#).is_err());
+    assert!(PikoAst::parse(r#"(o "\u{}")"#).is_err());
+}
+
+#[test]
+fn test_nesting_limit() {
+    let nested = |depth: usize| format!("{}\"a\"{}", "(o ".repeat(depth), ")".repeat(depth));
+    
+    assert!(Parser::parse_program(&nested(MAX_NESTING)).is_ok());
+    assert!(Cst::parse(&nested(MAX_NESTING)).unwrap().to_ast().is_ok());
+    
+    let error = Parser::parse_program(&nested(MAX_NESTING + 1)).unwrap_err();
+    assert_eq!(error.to_string(), format!(
+        "Parse error: Lists may nest at most {} deep at line 1, column {}", MAX_NESTING, MAX_NESTING * 3 + 1
+    ));
+    assert_eq!(Cst::parse(&nested(MAX_NESTING + 1)).unwrap_err().to_string(), error.to_string());
+    
+    let source = format!("(o \"before\")\n{}\n(o \"after\")", nested(50_000));
+    assert!(Parser::parse_program(&source).is_err());
+    assert!(Cst::parse(&source).is_err());
+    let (ast, errors) = Parser::parse_program_recovering(&source);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(ast.to_string(), "(o \"before\")\n(o \"after\")");
+}
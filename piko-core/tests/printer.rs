@@ -0,0 +1,123 @@
+use piko_core::ast::{format_source, Parser, PikoAst, PrintOptions, Printer};
+
+const EXAMPLES: &[&str] = &[
+    include_str!("../../examples/hello.pyx"),
+    include_str!("../../examples/variables.pyx"),
+    include_str!("../../examples/math.pyx"),
+    include_str!("../../examples/functions.pyx"),
+    include_str!("../../examples/loops.pyx"),
+    include_str!("../../examples/input.pyx"),
+    include_str!("../../examples/chains.pyx"),
+];
+
+fn forms(ast: PikoAst) -> Vec<PikoAst> {
+    match ast {
+        PikoAst::Program(forms) => forms,
+        ast => vec![ast],
+    }
+}
+
+#[test]
+fn test_round_trip_examples() {
+    for width in [10, 40, 80] {
+        let options = PrintOptions { indent: 2, width };
+        for source in EXAMPLES {
+            let ast = Parser::parse_program(source).unwrap();
+            let formatted = format_source(source, &options).unwrap();
+            assert_eq!(forms(Parser::parse_program(&formatted).unwrap()), forms(ast));
+            assert_eq!(format_source(&formatted, &options).unwrap(), formatted);
+        }
+    }
+}
+
+#[test]
+fn test_layout() {
+    let source = "(f   add x y\n (r   (+ x y)))\n(l (<= counter \"e\") (a counter (+ counter \"a\")) (o counter))";
+    assert_eq!(
+        format_source(source, &PrintOptions::default()).unwrap(),
+        "(f add x y (r (+ x y)))\n(l (<= counter \"e\") (a counter (+ counter \"a\")) (o counter))\n"
+    );
+    
+    let printer = Printer::new(PrintOptions { indent: 2, width: 24 });
+    let ast = Parser::parse_program(source).unwrap();
+    assert_eq!(
+        printer.print_ast(&ast),
+        "(f add x y (r (+ x y)))\n(l (<= counter \"e\")\n  (a counter\n    (+ counter \"a\"))\n  (o counter))"
+    );
+    
+    let source = "(f show (x y) (o x) (o y))\n(f hello () (o \"hello\"))\n(f id x (r x))\n";
+    assert_eq!(format_source(source, &PrintOptions::default()).unwrap(), source);
+    
+    assert_eq!(
+        format_source(source, &PrintOptions { indent: 2, width: 20 }).unwrap(),
+        "(f show (x y)\n  (o x)\n  (o y))\n(f hello ()\n  (o \"hello\"))\n(f id x (r x))\n"
+    );
+    
+    let source = "(? (> x y) (o x))\n(? x \"yes\" \"no\")\n";
+    assert_eq!(format_source(source, &PrintOptions::default()).unwrap(), source);
+    
+    let node = Parser::parse_expression(r#"(o "tab\there \"quoted\" \\ \u{7}")"#).unwrap();
+    assert_eq!(node.to_string(), r#"(o "tab\there \"quoted\" \\ \u{7}")"#);
+    
+    let node = Parser::parse_expression("(ao x (+ \"a\" \"b\"))").unwrap();
+    assert_eq!(node.to_string(), "(ao x (+ \"a\" \"b\"))");
+    assert_eq!(Parser::parse_expression(&node.to_string()).unwrap(), node);
+}
+
+#[test]
+fn test_keeps_comments() {
+    let source = "\
+# Adds two numbers
+
+
+(f add x y   # helper
+        (r (+ x y)))
+(o (c add \"a\" \"b\"))  # prints c
+(l (<= n \"e\")
+    # count up
+    (a n (+ n \"a\"))
+    (o n)
+    # done
+)
+
+# end
+";
+    let formatted = format_source(source, &PrintOptions::default()).unwrap();
+    assert_eq!(formatted, "\
+# Adds two numbers
+
+(f add x y  # helper
+    (r (+ x y)))
+(o (c add \"a\" \"b\"))  # prints c
+(l (<= n \"e\")
+    # count up
+    (a n (+ n \"a\"))
+    (o n)
+    # done
+)
+
+# end
+");
+    assert_eq!(format_source(&formatted, &PrintOptions::default()).unwrap(), formatted);
+    assert_eq!(forms(Parser::parse_program(&formatted).unwrap()), forms(Parser::parse_program(source).unwrap()));
+    
+    let comments = |source: &str| source.lines()
+        .filter_map(|line| line.find('#').map(|at| line[at..].to_string()))
+        .collect::<Vec<_>>();
+    assert_eq!(comments(&formatted), comments(source));
+    
+    let source = "(a x # note\n \"v\")\n(o (+ # inside\n x \"a\"))\n(o (# first\n + x \"a\"))\n";
+    assert_eq!(
+        format_source(source, &PrintOptions::default()).unwrap(),
+        "(a x  # note\n    \"v\")\n(o\n    (+  # inside\n        x\n        \"a\"))\n(o\n    # first\n    (+\n        x\n        \"a\"))\n"
+    );
+    
+    let source = "(f add (x y) # comment here\n  (r (+ x y)))\n(f sub (x y)\n  # doc\n  (r (- x y)))\n";
+    let formatted = format_source(source, &PrintOptions::default()).unwrap();
+    assert_eq!(formatted, "(f add (x y)  # comment here\n    (r (+ x y)))\n(f sub (x y)\n    # doc\n    (r (- x y)))\n");
+    assert_eq!(format_source(&formatted, &PrintOptions::default()).unwrap(), formatted);
+    assert_eq!(forms(Parser::parse_program(&formatted).unwrap()), forms(Parser::parse_program(source).unwrap()));
+    
+    let source = "(o \"# not a comment\")\n";
+    assert_eq!(format_source(source, &PrintOptions::default()).unwrap(), source);
+}
@@ -1,7 +1,6 @@
 use wasm_bindgen::prelude::*;
-use piko_core::Parseable;
-use piko_core::ast::PikoAst;
-use piko_core::vm::VM;
+use piko_core::ast::Parser;
+use piko_core::vm::{Limits, VM};
 
 const EXAMPLES: &[(&str, &str)] = &[
     ("hello", include_str!("../../examples/hello.pyx")),
@@ -13,6 +12,17 @@ const EXAMPLES: &[(&str, &str)] = &[
     ("chains", include_str!("../../examples/chains.pyx")),
 ];
 
+/// Steps a single run may take before it is stopped, so an endless loop
+/// reports an error instead of freezing the tab.
+const STEPS_PER_RUN: u64 = 10_000_000;
+
+/// Longest value a program may build. Fuel makes arithmetic on long values
+/// expensive, and this keeps a doubling value from exhausting memory.
+const MAX_VALUE_LEN: usize = 10_000;
+
+/// Output a single run may write before it is stopped.
+const MAX_OUTPUT_BYTES: usize = 1 << 20;
+
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(js_namespace = window)]
@@ -31,6 +41,12 @@ impl WebInput {
     }
 }
 
+impl Default for WebInput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl std::io::BufRead for WebInput {
     fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
         Ok(self.buffer.as_bytes())
@@ -70,83 +86,20 @@ impl PikoVM {
         let output = Vec::new();
         let input = WebInput::new();
         PikoVM {
-            vm: VM::new(output, input),
+            vm: VM::with_limits(output, input, Limits {
+                max_value_len: Some(MAX_VALUE_LEN),
+                max_output_bytes: Some(MAX_OUTPUT_BYTES),
+                ..Limits::default()
+            }),
         }
     }
     
     #[wasm_bindgen]
     pub fn execute(&mut self, code: &str) -> Result<(), JsValue> {
-        let statements = self.split_statements(code);
-        
-        for statement in statements {
-            let statement = statement.trim();
-            if statement.is_empty() || statement.starts_with('#') {
-                continue;
-            }
-            
-            match PikoAst::parse(statement) {
-                Ok(ast) => {
-                    self.vm.execute(ast).map_err(|e| JsValue::from_str(&e.to_string()))?;
-                }
-                Err(e) => return Err(JsValue::from_str(&e.to_string())),
-            }
-        }
-        Ok(())
-    }
-    
-    fn split_statements(&self, code: &str) -> Vec<String> {
-        let mut statements = Vec::new();
-        let mut current_statement = String::new();
-        let mut paren_count = 0;
-        let mut in_string = false;
-        let mut escape_next = false;
-        
-        for ch in code.chars() {
-            if escape_next {
-                current_statement.push(ch);
-                escape_next = false;
-                continue;
-            }
-            
-            match ch {
-                '\\' if in_string => {
-                    escape_next = true;
-                    current_statement.push(ch);
-                }
-                '"' => {
-                    in_string = !in_string;
-                    current_statement.push(ch);
-                }
-                '(' if !in_string => {
-                    paren_count += 1;
-                    current_statement.push(ch);
-                }
-                ')' if !in_string => {
-                    paren_count -= 1;
-                    current_statement.push(ch);
-                    
-                    if paren_count == 0 && !current_statement.trim().is_empty() {
-                        statements.push(current_statement.trim().to_string());
-                        current_statement.clear();
-                    }
-                }
-                '\n' if paren_count == 0 => {
-                    if !current_statement.trim().is_empty() {
-                        statements.push(current_statement.trim().to_string());
-                        current_statement.clear();
-                    }
-                }
-                _ => {
-                    current_statement.push(ch);
-                }
-            }
-        }
-        
-        if !current_statement.trim().is_empty() {
-            statements.push(current_statement.trim().to_string());
-        }
-        
-        statements
+        let ast = Parser::parse_program(code).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.vm.set_fuel(Some(STEPS_PER_RUN));
+        self.vm.reset_output_bytes();
+        self.vm.execute(ast).map_err(|e| JsValue::from_str(&e.to_string()))
     }
     
     #[wasm_bindgen]
@@ -158,6 +111,12 @@ impl PikoVM {
     }
 }
 
+impl Default for PikoVM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[wasm_bindgen]
 pub fn get_example(name: &str) -> Option<String> {
     EXAMPLES.iter()